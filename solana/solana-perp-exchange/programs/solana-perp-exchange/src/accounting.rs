@@ -0,0 +1,296 @@
+use anchor_lang::prelude::*;
+use crate::state::{ExchangeState, Market, UserAccount, VaultAccount, Position};
+use crate::error::PerpExchangeError;
+use crate::price::Price;
+
+/// Accounts touched when a position is opened, resized or closed
+pub struct PositionAccounts<'a> {
+    pub exchange_state: &'a mut ExchangeState,
+    pub market: &'a mut Market,
+    pub vault: &'a mut VaultAccount,
+    pub user_account: &'a mut UserAccount,
+}
+
+/// Realized result of closing all or part of a position
+pub struct Settlement {
+    /// P&L realized on the closed size
+    pub pnl: i128,
+    /// Closing fee charged
+    pub fee: u64,
+    /// Margin released from the position
+    pub released_margin: u64,
+    /// Collateral credited back to the user
+    pub payout: u64,
+    /// Funding settled before closing (positive = paid by the user)
+    pub funding: i64,
+}
+
+/// Settle the funding accrued on the position in `position_index` since its last
+/// settlement against the P&L pool. Payments come out of free collateral first
+/// and then out of the position margin; anything beyond both is forgiven.
+/// Returns the amount settled (positive = paid by the user).
+pub fn settle_funding(
+    market: &Market,
+    vault: &mut VaultAccount,
+    user_account: &mut UserAccount,
+    position_index: usize,
+) -> Result<i64> {
+    let position = &mut user_account.positions[position_index];
+    let owed = position.funding_owed(market.cumulative_funding_index)?;
+    position.funding_index = market.cumulative_funding_index;
+
+    let settled = if owed > 0 {
+        let owed = u64::try_from(owed).unwrap_or(u64::MAX);
+        let from_collateral = owed.min(user_account.collateral_balance);
+        let from_margin = (owed - from_collateral).min(position.margin);
+        if from_margin > 0 {
+            position.margin -= from_margin;
+            position.refresh_leverage();
+            vault.reserved_collateral = vault.reserved_collateral
+                .checked_sub(from_margin)
+                .ok_or(PerpExchangeError::MathOverflow)?;
+        }
+        user_account.collateral_balance -= from_collateral;
+
+        let paid = from_collateral + from_margin;
+        vault.pnl_pool = vault.pnl_pool
+            .checked_add(paid)
+            .ok_or(PerpExchangeError::MathOverflow)?;
+        i64::try_from(paid).map_err(|_| PerpExchangeError::MathOverflow)?
+    } else {
+        let received = u64::try_from(owed.unsigned_abs()).map_err(|_| PerpExchangeError::MathOverflow)?;
+        vault.pnl_pool = vault.pnl_pool
+            .checked_sub(received)
+            .ok_or(PerpExchangeError::InsufficientVaultBalance)?;
+        user_account.collateral_balance = user_account.collateral_balance
+            .checked_add(received)
+            .ok_or(PerpExchangeError::MathOverflow)?;
+        -i64::try_from(received).map_err(|_| PerpExchangeError::MathOverflow)?
+    };
+
+    user_account.funding_payment = user_account.funding_payment
+        .checked_add(settled)
+        .ok_or(PerpExchangeError::MathOverflow)?;
+    Ok(settled)
+}
+
+/// Open a position in `position_index`, or add to the one already there, at `price`,
+/// settling funding on the existing position first. The trading fee is charged on
+/// `margin` and returned.
+pub fn increase_position(
+    accounts: PositionAccounts,
+    position_index: usize,
+    is_long: bool,
+    margin: u64,
+    size: u64,
+    price: Price,
+    now: i64,
+) -> Result<u64> {
+    let PositionAccounts { exchange_state, market, vault, user_account } = accounts;
+
+    // Settle funding before the position changes size
+    if user_account.positions[position_index].is_open {
+        settle_funding(market, vault, user_account, position_index)?;
+    }
+
+    // Calculate trading fee
+    let trading_fee = market.trading_fee(margin)?;
+
+    // Check user has enough collateral including fees
+    let total_required = margin
+        .checked_add(trading_fee)
+        .ok_or(PerpExchangeError::MathOverflow)?;
+    require!(
+        user_account.collateral_balance >= total_required,
+        PerpExchangeError::InsufficientCollateral
+    );
+
+    // Number a newly opened position so orders placed on it stay bound to it
+    let opening = !user_account.positions[position_index].is_open;
+    apply_fill(market, &mut user_account.positions[position_index], is_long, margin, size, price, now)?;
+    if opening {
+        user_account.positions_opened = user_account.positions_opened
+            .checked_add(1)
+            .ok_or(PerpExchangeError::MathOverflow)?;
+        user_account.positions[position_index].sequence = user_account.positions_opened;
+    }
+
+    // Deduct margin and fees from user balance
+    user_account.collateral_balance -= total_required;
+    user_account.total_fees_paid = user_account.total_fees_paid
+        .checked_add(trading_fee)
+        .ok_or(PerpExchangeError::MathOverflow)?;
+
+    // Update vault reserved collateral
+    vault.reserved_collateral = vault.reserved_collateral
+        .checked_add(margin)
+        .ok_or(PerpExchangeError::MathOverflow)?;
+
+    // Update exchange and market statistics
+    exchange_state.collected_fees = exchange_state.collected_fees
+        .checked_add(trading_fee)
+        .ok_or(PerpExchangeError::MathOverflow)?;
+    market.add_open_interest(is_long, size)?;
+    market.record_volume(size)?;
+    market.record_fill_price(price, now);
+
+    Ok(trading_fee)
+}
+
+/// Add a fill of `size` at `price` backed by `margin` to `position`, opening it if it
+/// is not open yet. Only the position itself is updated.
+pub fn apply_fill(
+    market: &Market,
+    position: &mut Position,
+    is_long: bool,
+    margin: u64,
+    size: u64,
+    price: Price,
+    now: i64,
+) -> Result<()> {
+    if position.is_open {
+        require!(position.is_long() == is_long, PerpExchangeError::InvalidDirection);
+
+        // Blend the entry price weighted by base size, so P&L on the combined
+        // position equals the sum of P&L on its parts
+        let old_size = position.get_abs_size() as u128;
+        let old_price = u128::from(position.entry_price);
+        let new_size = old_size
+            .checked_add(size as u128)
+            .ok_or(PerpExchangeError::MathOverflow)?;
+        let numerator = new_size
+            .checked_mul(old_price)
+            .and_then(|v| v.checked_mul(u128::from(price)))
+            .ok_or(PerpExchangeError::MathOverflow)?;
+        let denominator = old_size
+            .checked_mul(u128::from(price))
+            .and_then(|v| v.checked_add((size as u128).checked_mul(old_price)?))
+            .ok_or(PerpExchangeError::MathOverflow)?;
+
+        let new_margin = position.margin
+            .checked_add(margin)
+            .ok_or(PerpExchangeError::MathOverflow)?;
+        let new_size = i64::try_from(new_size).map_err(|_| PerpExchangeError::MathOverflow)?;
+
+        // Round the blended entry against the position
+        let entry_price = if is_long {
+            numerator.div_ceil(denominator)
+        } else {
+            numerator / denominator
+        };
+        position.entry_price = Price::try_from(entry_price)?;
+        position.size = if is_long { new_size } else { -new_size };
+        position.margin = new_margin;
+        position.price_source = market.oracle_price_source;
+        position.refresh_leverage();
+    } else {
+        let signed_size = i64::try_from(size).map_err(|_| PerpExchangeError::MathOverflow)?;
+
+        *position = Position {
+            market_index: market.market_index,
+            size: if is_long { signed_size } else { -signed_size },
+            margin,
+            entry_price: price,
+            is_open: true,
+            leverage: (size / margin).min(u8::MAX as u64) as u8,
+            opened_at: now,
+            sequence: 0,
+            funding_index: market.cumulative_funding_index,
+            price_source: market.oracle_price_source,
+        };
+    }
+    Ok(())
+}
+
+/// Close `close_size` of the position in `position_index` at `price`, settling funding
+/// first, then realizing P&L on the closed portion only and releasing margin
+/// proportionally
+pub fn decrease_position(
+    accounts: PositionAccounts,
+    position_index: usize,
+    close_size: u64,
+    price: Price,
+    now: i64,
+) -> Result<Settlement> {
+    let PositionAccounts { exchange_state, market, vault, user_account } = accounts;
+    let funding = settle_funding(market, vault, user_account, position_index)?;
+    let position = &mut user_account.positions[position_index];
+    let abs_size = position.get_abs_size();
+    let is_long = position.is_long();
+
+    require!(close_size > 0 && close_size <= abs_size, PerpExchangeError::InvalidAmount);
+
+    // Release margin in proportion to the closed size
+    let released_margin = if close_size == abs_size {
+        position.margin
+    } else {
+        ((position.margin as u128) * (close_size as u128) / (abs_size as u128)) as u64
+    };
+
+    // Calculate P&L on the closed portion
+    let pnl = position.calculate_pnl_for_size(close_size, price)?;
+
+    // Calculate final margin after P&L; a loss beyond the margin cannot be collected
+    let margin_with_pnl = (released_margin as i128)
+        .checked_add(pnl)
+        .ok_or(PerpExchangeError::MathOverflow)?
+        .max(0) as u64;
+
+    // Calculate trading fee for closing, capped at what is left of the margin
+    let fee = market.trading_fee(released_margin)?.min(margin_with_pnl);
+    let payout = margin_with_pnl - fee;
+
+    // Settle P&L against the vault's P&L pool
+    if pnl > 0 {
+        vault.pnl_pool = vault.pnl_pool
+            .checked_sub(u64::try_from(pnl).map_err(|_| PerpExchangeError::MathOverflow)?)
+            .ok_or(PerpExchangeError::InsufficientVaultBalance)?;
+    } else {
+        let realized_loss = pnl.unsigned_abs().min(released_margin as u128) as u64;
+        vault.pnl_pool = vault.pnl_pool
+            .checked_add(realized_loss)
+            .ok_or(PerpExchangeError::MathOverflow)?;
+        if realized_loss == released_margin && released_margin > 0 {
+            msg!("Position closed with total loss exceeding margin");
+        }
+    }
+
+    // Shrink or clear the position
+    if close_size == abs_size {
+        *position = Position::default();
+    } else {
+        let remaining = (abs_size - close_size) as i64;
+        position.size = if is_long { remaining } else { -remaining };
+        position.margin -= released_margin;
+        position.price_source = market.oracle_price_source;
+    }
+
+    // Return collateral to user
+    user_account.collateral_balance = user_account.collateral_balance
+        .checked_add(payout)
+        .ok_or(PerpExchangeError::MathOverflow)?;
+    user_account.total_fees_paid = user_account.total_fees_paid
+        .checked_add(fee)
+        .ok_or(PerpExchangeError::MathOverflow)?;
+
+    // Update vault reserved collateral
+    vault.reserved_collateral = vault.reserved_collateral
+        .checked_sub(released_margin)
+        .ok_or(PerpExchangeError::MathOverflow)?;
+
+    // Update exchange and market statistics
+    exchange_state.collected_fees = exchange_state.collected_fees
+        .checked_add(fee)
+        .ok_or(PerpExchangeError::MathOverflow)?;
+    market.remove_open_interest(is_long, close_size)?;
+    market.record_volume(close_size)?;
+    market.record_fill_price(price, now);
+
+    Ok(Settlement {
+        pnl,
+        fee,
+        released_margin,
+        payout,
+        funding,
+    })
+}
@@ -0,0 +1,58 @@
+// Seeds for PDAs
+pub const EXCHANGE_STATE_SEED: &[u8] = b"exchange_state";
+pub const VAULT_SEED: &[u8] = b"vault";
+pub const VAULT_TOKEN_SEED: &[u8] = b"vault_token";
+pub const USER_ACCOUNT_SEED: &[u8] = b"user_account";
+pub const MARKET_SEED: &[u8] = b"market";
+pub const TRIGGER_ORDERS_SEED: &[u8] = b"trigger_orders";
+pub const FUNDING_HISTORY_SEED: &[u8] = b"funding_history";
+pub const PUBLISHER_SET_SEED: &[u8] = b"publisher_set";
+pub const PRICE_HISTORY_SEED: &[u8] = b"price_history";
+pub const INSURANCE_FUND_SEED: &[u8] = b"insurance_fund";
+pub const INSURANCE_FUND_TOKEN_SEED: &[u8] = b"insurance_fund_token";
+
+// Default governance parameters
+pub const DEFAULT_TRADING_FEE_RATE: u16 = 100; // 1% (100 basis points)
+pub const DEFAULT_MAX_LEVERAGE: u8 = 10;
+pub const DEFAULT_MIN_MARGIN: u64 = 1; // 1 whole collateral token, scaled by the mint decimals
+pub const DEFAULT_SKEW_SCALE: u64 = 1_000_000; // 1M whole collateral tokens, scaled by the mint decimals
+pub const DEFAULT_INITIAL_MARGIN_RATE: u16 = 1000; // 10% of notional, matching the default max leverage
+pub const DEFAULT_MAINTENANCE_MARGIN_RATE: u16 = 500; // 5% of notional
+pub const DEFAULT_LIQUIDATION_BUFFER_RATE: u16 = 100; // partial liquidations restore 1% of notional above maintenance
+pub const DEFAULT_LIQUIDATION_REWARD_RATE: u16 = 100; // 1% of the liquidated margin
+pub const DEFAULT_KEEPER_FEE_RATE: u16 = 10; // 0.1% of the released margin
+pub const DEFAULT_FUNDING_INTERVAL: u32 = 3600; // 1 hour in seconds
+pub const DEFAULT_MAX_FUNDING_RATE: u16 = 10; // 0.1% per interval (10 basis points)
+pub const DEFAULT_FUNDING_DAMPENING: u16 = 24; // premium converges over roughly a day of hourly intervals
+pub const DEFAULT_ORACLE_VALIDITY_PERIOD: u32 = 300; // 5 minutes in seconds
+pub const DEFAULT_MARK_EMA_PERIOD: u32 = 300; // 5 minutes in seconds
+pub const DEFAULT_MAX_CONFIDENCE_RATE: u16 = 200; // 2% of the price (200 basis points)
+pub const DEFAULT_MAX_PRICE_MOVE_RATE: u16 = 1000; // 10% per update (1000 basis points)
+pub const DEFAULT_MAX_WINDOW_MOVE_RATE: u16 = 2500; // 25% per price window (2500 basis points)
+pub const DEFAULT_PRICE_WINDOW: u32 = 3600; // 1 hour in seconds
+pub const DEFAULT_MAX_FILL_DEVIATION_RATE: u16 = 500; // 5% from the index price (500 basis points)
+
+// Market limits
+pub const MAX_SYMBOL_LEN: usize = 16;
+pub const MAX_POSITIONS: usize = 8; // open positions per user account
+pub const MAX_ORDERS_PER_SIDE: usize = 128; // resting orders per order book side
+pub const MAX_TRIGGER_ORDERS: usize = 16; // trigger orders per user
+pub const MAX_PUBLISHERS: usize = 16; // price publishers per market
+pub const MAX_ORACLE_SOURCES: usize = 3; // oracle sources in a market's fallback chain
+pub const PRICE_HISTORY_LEN: usize = 128; // index price observations kept per market
+
+// Funding
+pub const FUNDING_RATE_PRECISION: i128 = 1_000_000_000; // funding rates are fractions scaled by 1e9
+pub const FUNDING_HISTORY_LEN: usize = 64; // funding updates kept per market
+
+// Virtual AMM
+pub const VAMM_PRICE_SCALE: u128 = 1_000 * PRICE_PRECISION as u128; // vAMM price = quote reserve * scale / base reserve
+
+// Skew premium
+pub const MAX_SKEW_PREMIUM_RATE: u16 = 5000; // skew premiums are capped at 50% of the price (5000 basis points)
+
+// Prices
+pub const PRICE_DECIMALS: u32 = 9; // prices are fixed-point decimals with 9 decimal places
+pub const PRICE_PRECISION: u64 = 1_000_000_000; // fixed-point price of 1.0
+pub const MIN_PRICE_EXPONENT: i32 = -18; // finest raw oracle price unit accepted (10^-18)
+pub const MAX_PRICE_EXPONENT: i32 = 0; // coarsest raw oracle price unit accepted (whole units)
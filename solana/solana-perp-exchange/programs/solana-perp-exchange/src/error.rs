@@ -0,0 +1,127 @@
+use anchor_lang::prelude::*;
+
+#[error_code]
+pub enum PerpExchangeError {
+    #[msg("Invalid price provided")]
+    InvalidPrice,
+
+    #[msg("Invalid amount provided")]
+    InvalidAmount,
+
+    #[msg("Insufficient collateral")]
+    InsufficientCollateral,
+
+    #[msg("Invalid leverage provided")]
+    InvalidLeverage,
+
+    #[msg("Margin is below the minimum requirement")]
+    MarginTooLow,
+
+    #[msg("Position already exists")]
+    PositionExists,
+
+    #[msg("No position to close")]
+    NoPosition,
+
+    #[msg("Oracle price is stale")]
+    StaleOracle,
+
+    #[msg("Math overflow")]
+    MathOverflow,
+
+    #[msg("Unauthorized user")]
+    UnauthorizedUser,
+
+    #[msg("Unauthorized admin")]
+    UnauthorizedAdmin,
+
+    #[msg("Trading is paused")]
+    TradingPaused,
+
+    #[msg("Position is underwater")]
+    PositionUnderwater,
+
+    #[msg("Position is not liquidatable")]
+    PositionNotLiquidatable,
+
+    #[msg("Invalid liquidation")]
+    InvalidLiquidation,
+
+    #[msg("Insufficient vault balance")]
+    InsufficientVaultBalance,
+
+    #[msg("Invalid parameter provided")]
+    InvalidParameter,
+
+    #[msg("Vault balance does not match the tokens held in custody")]
+    VaultBalanceMismatch,
+
+    #[msg("Invalid market symbol")]
+    InvalidSymbol,
+
+    #[msg("Maximum number of open positions reached")]
+    TooManyPositions,
+
+    #[msg("Order direction does not match the open position")]
+    InvalidDirection,
+
+    #[msg("Position would fall below the initial margin requirement")]
+    InitialMarginNotMet,
+
+    #[msg("Fill price is outside the requested bounds")]
+    SlippageExceeded,
+
+    #[msg("Transaction deadline has passed")]
+    DeadlineExceeded,
+
+    #[msg("Order book is full")]
+    OrderBookFull,
+
+    #[msg("Order not found")]
+    OrderNotFound,
+
+    #[msg("Order book does not belong to this market")]
+    InvalidOrderBook,
+
+    #[msg("Best bid and ask do not cross")]
+    OrdersNotCrossing,
+
+    #[msg("Order would cross one of the owner's own orders")]
+    SelfTrade,
+
+    #[msg("Maximum number of trigger orders reached")]
+    TooManyTriggerOrders,
+
+    #[msg("Trigger condition is not met")]
+    TriggerConditionNotMet,
+
+    #[msg("Not enough vAMM liquidity for this trade")]
+    InsufficientLiquidity,
+
+    #[msg("Funding interval has not elapsed")]
+    FundingNotDue,
+
+    #[msg("Oracle account is not a valid price feed for this market")]
+    InvalidOracle,
+
+    #[msg("Oracle confidence interval is too wide")]
+    OracleConfidenceTooWide,
+
+    #[msg("Signer is not an authorized price publisher")]
+    UnauthorizedPublisher,
+
+    #[msg("Maximum number of publishers reached")]
+    TooManyPublishers,
+
+    #[msg("Market is halted by the price circuit breaker")]
+    MarketHalted,
+
+    #[msg("Price history does not cover the requested window")]
+    InsufficientPriceHistory,
+
+    #[msg("Trigger order belongs to a position that has been closed")]
+    StaleTriggerOrder,
+
+    #[msg("Market has open positions")]
+    MarketHasOpenInterest,
+}
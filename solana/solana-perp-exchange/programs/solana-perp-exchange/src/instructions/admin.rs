@@ -0,0 +1,393 @@
+use anchor_lang::prelude::*;
+use anchor_spl::token::{Mint, Token, TokenAccount};
+use crate::state::{
+    ExchangeState, Market, VaultAccount, GovernanceParams, OracleSource, PriceHistory, PricingMode,
+    SourcePrice,
+};
+use crate::oracle::log_price_update;
+use crate::constants::*;
+use crate::error::PerpExchangeError;
+use crate::price::Price;
+use crate::utils::transfer_to_vault;
+
+/// List a new market (admin only)
+#[derive(Accounts)]
+pub struct ListMarket<'info> {
+    #[account(
+        mut,
+        seeds = [EXCHANGE_STATE_SEED],
+        bump,
+        constraint = exchange_state.admin == admin.key() @ PerpExchangeError::UnauthorizedAdmin
+    )]
+    pub exchange_state: Account<'info, ExchangeState>,
+
+    #[account(
+        init,
+        payer = admin,
+        space = Market::SPACE,
+        seeds = [MARKET_SEED, &exchange_state.market_count.to_le_bytes()],
+        bump
+    )]
+    pub market: Account<'info, Market>,
+
+    #[account(
+        init,
+        payer = admin,
+        space = PriceHistory::SPACE,
+        seeds = [PRICE_HISTORY_SEED, &exchange_state.market_count.to_le_bytes()],
+        bump
+    )]
+    pub price_history: Box<Account<'info, PriceHistory>>,
+
+    #[account(mut)]
+    pub admin: Signer<'info>,
+
+    pub system_program: Program<'info, System>,
+}
+
+/// Parameters for listing a market
+#[derive(AnchorSerialize, AnchorDeserialize)]
+pub struct ListMarketParams {
+    pub symbol: String,
+    /// Exponent of the raw prices reported by the market's oracle sources
+    pub price_exponent: i32,
+    /// Initial raw oracle price, in units of 10^`price_exponent`
+    pub oracle_price: u64,
+    /// Governance parameters for the market, defaults are used when omitted
+    pub governance_params: Option<GovernanceParams>,
+}
+
+pub fn handle_list_market(ctx: Context<ListMarket>, params: ListMarketParams) -> Result<()> {
+    let exchange_state = &mut ctx.accounts.exchange_state;
+    let market = &mut ctx.accounts.market;
+    let clock = Clock::get()?;
+
+    require!(
+        !params.symbol.is_empty() && params.symbol.len() <= MAX_SYMBOL_LEN,
+        PerpExchangeError::InvalidSymbol
+    );
+
+    let governance_params = match params.governance_params {
+        Some(governance_params) => governance_params,
+        None => {
+            let unit = 10u64
+                .checked_pow(exchange_state.collateral_decimals as u32)
+                .ok_or(PerpExchangeError::MathOverflow)?;
+            let min_margin = unit
+                .checked_mul(DEFAULT_MIN_MARGIN)
+                .ok_or(PerpExchangeError::MathOverflow)?;
+            let skew_scale = unit
+                .checked_mul(DEFAULT_SKEW_SCALE)
+                .ok_or(PerpExchangeError::MathOverflow)?;
+
+            GovernanceParams {
+                trading_fee_rate: DEFAULT_TRADING_FEE_RATE,
+                initial_margin_rate: DEFAULT_INITIAL_MARGIN_RATE,
+                maintenance_margin_rate: DEFAULT_MAINTENANCE_MARGIN_RATE,
+                liquidation_buffer_rate: DEFAULT_LIQUIDATION_BUFFER_RATE,
+                liquidation_reward_rate: DEFAULT_LIQUIDATION_REWARD_RATE,
+                keeper_fee_rate: DEFAULT_KEEPER_FEE_RATE,
+                max_leverage: DEFAULT_MAX_LEVERAGE,
+                min_margin,
+                skew_scale,
+                funding_interval: DEFAULT_FUNDING_INTERVAL,
+                max_funding_rate: DEFAULT_MAX_FUNDING_RATE,
+                funding_dampening: DEFAULT_FUNDING_DAMPENING,
+                oracle_validity_period: DEFAULT_ORACLE_VALIDITY_PERIOD,
+                mark_ema_period: DEFAULT_MARK_EMA_PERIOD,
+                max_confidence_rate: DEFAULT_MAX_CONFIDENCE_RATE,
+                max_price_move_rate: DEFAULT_MAX_PRICE_MOVE_RATE,
+                max_window_move_rate: DEFAULT_MAX_WINDOW_MOVE_RATE,
+                price_window: DEFAULT_PRICE_WINDOW,
+                max_fill_deviation_rate: DEFAULT_MAX_FILL_DEVIATION_RATE,
+            }
+        }
+    };
+    governance_params.validate()?;
+
+    let mut symbol = [0u8; MAX_SYMBOL_LEN];
+    symbol[..params.symbol.len()].copy_from_slice(params.symbol.as_bytes());
+
+    // Initialize market
+    market.exchange_state = exchange_state.key();
+    market.market_index = exchange_state.market_count;
+    market.symbol = symbol;
+    market.price_exponent = params.price_exponent;
+    let oracle_price = market.normalize_price(params.oracle_price)?;
+    market.oracle_price = oracle_price;
+    market.oracle_last_update = clock.unix_timestamp;
+    market.mark_price_ema = oracle_price;
+    market.mark_price_last_update = clock.unix_timestamp;
+    market.oracle_sources = [OracleSource::Admin; MAX_ORACLE_SOURCES];
+    market.oracle_source_count = 1;
+    market.source_prices = [SourcePrice::default(); MAX_ORACLE_SOURCES];
+    market.oracle_price_source = OracleSource::Admin;
+    market.oracle_feed = Pubkey::default();
+    market.oracle_program = Pubkey::default();
+    market.oracle_confidence = 0;
+    market.is_halted = false;
+    market.pending_price = Price::ZERO;
+    market.pending_price_reporter = Pubkey::default();
+    market.pending_price_source = OracleSource::Admin;
+    market.price_window_start = clock.unix_timestamp;
+    market.price_window_open_price = oracle_price;
+    market.funding_rate = 0;
+    market.funding_last_update = clock.unix_timestamp;
+    market.cumulative_funding_index = 0;
+    market.governance_params = governance_params;
+    market.total_long_positions = 0;
+    market.total_short_positions = 0;
+    market.total_volume = 0;
+    market.pricing_mode = PricingMode::Oracle;
+    market.vamm_base_reserve = 0;
+    market.vamm_quote_reserve = 0;
+    market.bump = ctx.bumps.market;
+
+    let price_history = &mut ctx.accounts.price_history;
+    price_history.market_index = market.market_index;
+    price_history.clear();
+    price_history.record(oracle_price, clock.unix_timestamp);
+
+    exchange_state.market_count = exchange_state.market_count
+        .checked_add(1)
+        .ok_or(PerpExchangeError::MathOverflow)?;
+
+    msg!(
+        "Market {} listed: {} at oracle price {}",
+        market.market_index,
+        params.symbol,
+        oracle_price
+    );
+    Ok(())
+}
+
+/// Update a market's oracle price (admin only)
+#[derive(Accounts)]
+pub struct UpdatePrice<'info> {
+    #[account(
+        seeds = [EXCHANGE_STATE_SEED],
+        bump,
+        constraint = exchange_state.admin == admin.key() @ PerpExchangeError::UnauthorizedAdmin
+    )]
+    pub exchange_state: Account<'info, ExchangeState>,
+
+    #[account(
+        mut,
+        seeds = [MARKET_SEED, &market.market_index.to_le_bytes()],
+        bump = market.bump
+    )]
+    pub market: Account<'info, Market>,
+
+    #[account(
+        mut,
+        seeds = [PRICE_HISTORY_SEED, &market.market_index.to_le_bytes()],
+        bump
+    )]
+    pub price_history: Box<Account<'info, PriceHistory>>,
+
+    #[account(mut)]
+    pub admin: Signer<'info>,
+}
+
+pub fn handle_update_price(ctx: Context<UpdatePrice>, new_price: u64) -> Result<()> {
+    let market = &mut ctx.accounts.market;
+    let clock = Clock::get()?;
+
+    let price = market.normalize_price(new_price)?;
+
+    let update = market.record_source_price(
+        OracleSource::Admin,
+        SourcePrice {
+            price,
+            confidence: 0,
+            timestamp: clock.unix_timestamp,
+            reporter: ctx.accounts.admin.key(),
+        },
+        clock.unix_timestamp,
+    )?;
+    log_price_update(market, &mut ctx.accounts.price_history, update);
+    Ok(())
+}
+
+pub fn handle_confirm_oracle_price(ctx: Context<UpdatePrice>) -> Result<()> {
+    let market = &mut ctx.accounts.market;
+    let clock = Clock::get()?;
+
+    // The admin vouches for the price that tripped the breaker
+    let price = market.confirm_pending_price(clock.unix_timestamp)?;
+    ctx.accounts.price_history.record(price, clock.unix_timestamp);
+
+    msg!("Oracle price for market {} confirmed at {}, trading resumed", market.market_index, price);
+    Ok(())
+}
+
+/// Seed the vault's P&L pool so trader profits can be paid out (admin only)
+#[derive(Accounts)]
+pub struct FundPnlPool<'info> {
+    #[account(
+        seeds = [EXCHANGE_STATE_SEED],
+        bump,
+        constraint = exchange_state.admin == admin.key() @ PerpExchangeError::UnauthorizedAdmin
+    )]
+    pub exchange_state: Account<'info, ExchangeState>,
+
+    #[account(
+        mut,
+        seeds = [VAULT_SEED],
+        bump = vault.bump
+    )]
+    pub vault: Account<'info, VaultAccount>,
+
+    #[account(
+        mut,
+        address = vault.token_account
+    )]
+    pub vault_token_account: Account<'info, TokenAccount>,
+
+    #[account(address = vault_token_account.mint)]
+    pub collateral_mint: Account<'info, Mint>,
+
+    #[account(
+        mut,
+        token::mint = collateral_mint,
+        token::authority = admin
+    )]
+    pub admin_token_account: Account<'info, TokenAccount>,
+
+    pub admin: Signer<'info>,
+
+    pub token_program: Program<'info, Token>,
+}
+
+pub fn handle_fund_pnl_pool(ctx: Context<FundPnlPool>, amount: u64) -> Result<()> {
+    require!(amount > 0, PerpExchangeError::InvalidAmount);
+
+    // Transfer tokens from admin to vault
+    transfer_to_vault(
+        &ctx.accounts.admin_token_account,
+        &ctx.accounts.vault_token_account,
+        &ctx.accounts.collateral_mint,
+        &ctx.accounts.admin,
+        &ctx.accounts.token_program,
+        amount,
+    )?;
+
+    let vault = &mut ctx.accounts.vault;
+    vault.pnl_pool = vault.pnl_pool
+        .checked_add(amount)
+        .ok_or(PerpExchangeError::MathOverflow)?;
+    vault.total_balance = vault.total_balance
+        .checked_add(amount)
+        .ok_or(PerpExchangeError::MathOverflow)?;
+
+    // Reconcile the vault ledger with custody
+    ctx.accounts.vault_token_account.reload()?;
+    ctx.accounts.vault.check_reconciled(ctx.accounts.vault_token_account.amount)?;
+
+    msg!("P&L pool funded with {} collateral units", amount);
+    Ok(())
+}
+
+/// Update a market's governance parameters (admin only)
+#[derive(Accounts)]
+pub struct UpdateGovernanceParams<'info> {
+    #[account(
+        seeds = [EXCHANGE_STATE_SEED],
+        bump,
+        constraint = exchange_state.admin == admin.key() @ PerpExchangeError::UnauthorizedAdmin
+    )]
+    pub exchange_state: Account<'info, ExchangeState>,
+
+    #[account(
+        mut,
+        seeds = [MARKET_SEED, &market.market_index.to_le_bytes()],
+        bump = market.bump
+    )]
+    pub market: Account<'info, Market>,
+
+    pub admin: Signer<'info>,
+}
+
+pub fn handle_update_governance_params(
+    ctx: Context<UpdateGovernanceParams>,
+    params: GovernanceParams,
+) -> Result<()> {
+    params.validate()?;
+
+    let market = &mut ctx.accounts.market;
+    market.governance_params = params;
+
+    msg!("Governance parameters updated for market {}", market.market_index);
+    Ok(())
+}
+
+/// Parameters for switching a market's pricing mode
+#[derive(AnchorSerialize, AnchorDeserialize)]
+pub struct SetPricingModeParams {
+    pub mode: PricingMode,
+    /// Virtual quote reserve (in collateral base units); sets the depth of the
+    /// vAMM, ignored in oracle mode
+    pub quote_reserve: u128,
+}
+
+pub fn handle_set_pricing_mode(
+    ctx: Context<UpdateGovernanceParams>,
+    params: SetPricingModeParams,
+) -> Result<()> {
+    let market = &mut ctx.accounts.market;
+
+    // Open positions were priced against the current reserves and exit against them
+    require!(
+        market.total_long_positions == 0 && market.total_short_positions == 0,
+        PerpExchangeError::MarketHasOpenInterest
+    );
+
+    market.pricing_mode = params.mode;
+    if params.mode == PricingMode::Vamm {
+        require!(params.quote_reserve > 0, PerpExchangeError::InvalidParameter);
+        market.reset_vamm(params.quote_reserve)?;
+    } else {
+        market.vamm_quote_reserve = 0;
+        market.vamm_base_reserve = 0;
+    }
+
+    msg!(
+        "Pricing mode updated for market {} - Quote reserve: {}, Base reserve: {}",
+        market.market_index,
+        market.vamm_quote_reserve,
+        market.vamm_base_reserve
+    );
+    Ok(())
+}
+
+/// Re-peg a market's vAMM reserves to the oracle price (permissionless)
+#[derive(Accounts)]
+pub struct RepegVamm<'info> {
+    #[account(
+        mut,
+        seeds = [MARKET_SEED, &market.market_index.to_le_bytes()],
+        bump = market.bump
+    )]
+    pub market: Account<'info, Market>,
+
+    /// The keeper or admin (can be anyone)
+    pub keeper: Signer<'info>,
+}
+
+pub fn handle_repeg_vamm(ctx: Context<RepegVamm>) -> Result<()> {
+    let market = &mut ctx.accounts.market;
+    let clock = Clock::get()?;
+
+    require!(market.pricing_mode == PricingMode::Vamm, PerpExchangeError::InvalidParameter);
+    market.check_not_halted()?;
+    market.check_oracle_fresh(clock.unix_timestamp)?;
+    market.repeg()?;
+
+    msg!(
+        "vAMM re-pegged for market {} at oracle price {} - Quote reserve: {}",
+        market.market_index,
+        market.oracle_price,
+        market.vamm_quote_reserve
+    );
+    Ok(())
+}
@@ -0,0 +1,130 @@
+use anchor_lang::prelude::*;
+use crate::state::{ExchangeState, FundingHistory, FundingRecord, Market};
+use crate::constants::*;
+use crate::error::PerpExchangeError;
+
+/// Create the funding history of a market (admin only)
+#[derive(Accounts)]
+pub struct CreateFundingHistory<'info> {
+    #[account(
+        seeds = [EXCHANGE_STATE_SEED],
+        bump,
+        constraint = exchange_state.admin == admin.key() @ PerpExchangeError::UnauthorizedAdmin
+    )]
+    pub exchange_state: Account<'info, ExchangeState>,
+
+    #[account(
+        seeds = [MARKET_SEED, &market.market_index.to_le_bytes()],
+        bump = market.bump
+    )]
+    pub market: Account<'info, Market>,
+
+    #[account(
+        init,
+        payer = admin,
+        space = FundingHistory::SPACE,
+        seeds = [FUNDING_HISTORY_SEED, &market.market_index.to_le_bytes()],
+        bump
+    )]
+    pub funding_history: Box<Account<'info, FundingHistory>>,
+
+    #[account(mut)]
+    pub admin: Signer<'info>,
+
+    pub system_program: Program<'info, System>,
+}
+
+pub fn handle_create_funding_history(ctx: Context<CreateFundingHistory>) -> Result<()> {
+    let funding_history = &mut ctx.accounts.funding_history;
+
+    funding_history.market_index = ctx.accounts.market.market_index;
+    funding_history.head = 0;
+    funding_history.len = 0;
+    funding_history.records = [FundingRecord::default(); FUNDING_HISTORY_LEN];
+
+    msg!("Funding history created for market {}", funding_history.market_index);
+    Ok(())
+}
+
+/// Apply the funding rate for every elapsed funding interval (permissionless crank)
+#[derive(Accounts)]
+pub struct UpdateFunding<'info> {
+    #[account(
+        mut,
+        seeds = [MARKET_SEED, &market.market_index.to_le_bytes()],
+        bump = market.bump
+    )]
+    pub market: Account<'info, Market>,
+
+    #[account(
+        mut,
+        seeds = [FUNDING_HISTORY_SEED, &market.market_index.to_le_bytes()],
+        bump
+    )]
+    pub funding_history: Box<Account<'info, FundingHistory>>,
+
+    pub cranker: Signer<'info>,
+}
+
+pub fn handle_update_funding(ctx: Context<UpdateFunding>) -> Result<()> {
+    let market = &mut ctx.accounts.market;
+    let clock = Clock::get()?;
+
+    // Check a funding interval has passed since the last update
+    let funding_interval = market.governance_params.funding_interval as i64;
+    let intervals = (clock.unix_timestamp - market.funding_last_update) / funding_interval;
+    require!(intervals > 0, PerpExchangeError::FundingNotDue);
+
+    // Check the market is not halted and the oracle price is fresh
+    market.check_not_halted()?;
+    market.check_oracle_fresh(clock.unix_timestamp)?;
+
+    // Positions settle against the index lazily the next time they are touched
+    let premium = market.funding_premium()?;
+    let funding_rate = market.funding_rate_for_premium(premium);
+    market.cumulative_funding_index = (funding_rate as i128)
+        .checked_mul(intervals as i128)
+        .and_then(|accrued| market.cumulative_funding_index.checked_add(accrued))
+        .ok_or(PerpExchangeError::MathOverflow)?;
+    market.funding_rate = funding_rate;
+    market.funding_last_update = market.funding_last_update
+        .checked_add(intervals * funding_interval)
+        .ok_or(PerpExchangeError::MathOverflow)?;
+
+    ctx.accounts.funding_history.push(FundingRecord {
+        funding_rate,
+        premium,
+        long_open_interest: market.total_long_positions,
+        short_open_interest: market.total_short_positions,
+        intervals: u32::try_from(intervals).map_err(|_| PerpExchangeError::MathOverflow)?,
+        timestamp: clock.unix_timestamp,
+    });
+
+    msg!(
+        "Funding updated for market {} - Rate: {}, Premium: {}, Intervals: {}, Cumulative index: {}",
+        market.market_index,
+        funding_rate,
+        premium,
+        intervals,
+        market.cumulative_funding_index
+    );
+    Ok(())
+}
+
+/// Read-only view of a market's funding
+#[derive(Accounts)]
+pub struct ViewFunding<'info> {
+    #[account(
+        seeds = [MARKET_SEED, &market.market_index.to_le_bytes()],
+        bump = market.bump
+    )]
+    pub market: Account<'info, Market>,
+}
+
+/// Rate the next funding update would apply if the market stayed as it is now
+/// (scaled by FUNDING_RATE_PRECISION, positive = longs pay shorts)
+pub fn handle_predicted_funding_rate(ctx: Context<ViewFunding>) -> Result<i64> {
+    let market = &ctx.accounts.market;
+    let premium = market.funding_premium()?;
+    Ok(market.funding_rate_for_premium(premium))
+}
@@ -0,0 +1,97 @@
+use anchor_lang::prelude::*;
+use anchor_spl::token::{Mint, Token, TokenAccount};
+use crate::state::{ExchangeState, VaultAccount, InsuranceFund};
+use crate::constants::*;
+
+/// Initialize the exchange state, vault and insurance fund for a collateral mint
+#[derive(Accounts)]
+pub struct Initialize<'info> {
+    #[account(
+        init,
+        payer = admin,
+        space = ExchangeState::SPACE,
+        seeds = [EXCHANGE_STATE_SEED],
+        bump
+    )]
+    pub exchange_state: Account<'info, ExchangeState>,
+
+    #[account(
+        init,
+        payer = admin,
+        space = VaultAccount::SPACE,
+        seeds = [VAULT_SEED],
+        bump
+    )]
+    pub vault: Account<'info, VaultAccount>,
+
+    #[account(
+        init,
+        payer = admin,
+        space = InsuranceFund::SPACE,
+        seeds = [INSURANCE_FUND_SEED],
+        bump
+    )]
+    pub insurance_fund: Account<'info, InsuranceFund>,
+
+    pub collateral_mint: Account<'info, Mint>,
+
+    #[account(
+        init,
+        payer = admin,
+        seeds = [VAULT_TOKEN_SEED],
+        bump,
+        token::mint = collateral_mint,
+        token::authority = vault
+    )]
+    pub vault_token_account: Account<'info, TokenAccount>,
+
+    #[account(
+        init,
+        payer = admin,
+        seeds = [INSURANCE_FUND_TOKEN_SEED],
+        bump,
+        token::mint = collateral_mint,
+        token::authority = insurance_fund
+    )]
+    pub insurance_fund_token_account: Account<'info, TokenAccount>,
+
+    #[account(mut)]
+    pub admin: Signer<'info>,
+
+    pub token_program: Program<'info, Token>,
+    pub system_program: Program<'info, System>,
+}
+
+pub fn handle_initialize(ctx: Context<Initialize>) -> Result<()> {
+    let exchange_state = &mut ctx.accounts.exchange_state;
+    let vault = &mut ctx.accounts.vault;
+    let insurance_fund = &mut ctx.accounts.insurance_fund;
+    let collateral_mint = &ctx.accounts.collateral_mint;
+
+    // Initialize exchange state
+    exchange_state.admin = ctx.accounts.admin.key();
+    exchange_state.vault = vault.key();
+    exchange_state.collateral_mint = collateral_mint.key();
+    exchange_state.collateral_decimals = collateral_mint.decimals;
+    exchange_state.collected_fees = 0;
+    exchange_state.insurance_fund = insurance_fund.key();
+    exchange_state.is_paused = false;
+    exchange_state.market_count = 0;
+
+    // Initialize vault
+    vault.exchange_state = exchange_state.key();
+    vault.token_account = ctx.accounts.vault_token_account.key();
+    vault.total_balance = 0;
+    vault.reserved_collateral = 0;
+    vault.pnl_pool = 0;
+    vault.bump = ctx.bumps.vault;
+
+    // Initialize insurance fund
+    insurance_fund.exchange_state = exchange_state.key();
+    insurance_fund.token_account = ctx.accounts.insurance_fund_token_account.key();
+    insurance_fund.balance = 0;
+    insurance_fund.bump = ctx.bumps.insurance_fund;
+
+    msg!("Exchange initialized with collateral mint: {}", collateral_mint.key());
+    Ok(())
+}
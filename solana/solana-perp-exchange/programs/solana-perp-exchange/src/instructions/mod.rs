@@ -2,8 +2,16 @@ pub mod initialize;
 pub mod user_management;
 pub mod trading;
 pub mod admin;
+pub mod orders;
+pub mod triggers;
+pub mod funding;
+pub mod oracle;
 
 pub use initialize::*;
 pub use user_management::*;
 pub use trading::*;
 pub use admin::*;
+pub use orders::*;
+pub use triggers::*;
+pub use funding::*;
+pub use oracle::*;
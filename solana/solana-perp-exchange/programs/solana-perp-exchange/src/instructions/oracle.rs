@@ -0,0 +1,371 @@
+use anchor_lang::prelude::*;
+use crate::state::{
+    ExchangeState, Market, OracleSource, PriceHistory, PriceSubmission, PriceUpdate, PublisherSet,
+    SourcePrice,
+};
+use crate::oracle::{apply_pyth_price, load_pyth_price, log_price_update};
+use crate::constants::*;
+use crate::error::PerpExchangeError;
+use crate::price::Price;
+
+/// Configure a market's oracle fallback chain (admin only)
+#[derive(Accounts)]
+pub struct SetOracleSources<'info> {
+    #[account(
+        seeds = [EXCHANGE_STATE_SEED],
+        bump,
+        constraint = exchange_state.admin == admin.key() @ PerpExchangeError::UnauthorizedAdmin
+    )]
+    pub exchange_state: Account<'info, ExchangeState>,
+
+    #[account(
+        mut,
+        seeds = [MARKET_SEED, &market.market_index.to_le_bytes()],
+        bump = market.bump
+    )]
+    pub market: Account<'info, Market>,
+
+    #[account(
+        mut,
+        seeds = [PRICE_HISTORY_SEED, &market.market_index.to_le_bytes()],
+        bump
+    )]
+    pub price_history: Box<Account<'info, PriceHistory>>,
+
+    /// Pyth-format price account, ignored unless Pyth is one of the sources
+    /// CHECK: Owner and layout are validated when the price is read
+    pub price_feed: AccountInfo<'info>,
+
+    pub admin: Signer<'info>,
+}
+
+/// Parameters for configuring a market's oracle fallback chain
+#[derive(AnchorSerialize, AnchorDeserialize)]
+pub struct SetOracleSourcesParams {
+    /// Sources in order of preference. All sources must report raw prices in
+    /// the market's price exponent.
+    pub sources: Vec<OracleSource>,
+    /// Program that must own the price account (Pyth source only)
+    pub oracle_program: Pubkey,
+}
+
+pub fn handle_set_oracle_sources(
+    ctx: Context<SetOracleSources>,
+    params: SetOracleSourcesParams,
+) -> Result<()> {
+    let market = &mut ctx.accounts.market;
+    let clock = Clock::get()?;
+
+    require!(
+        !params.sources.is_empty() && params.sources.len() <= MAX_ORACLE_SOURCES,
+        PerpExchangeError::InvalidParameter
+    );
+    for (index, source) in params.sources.iter().enumerate() {
+        require!(
+            !params.sources[..index].contains(source),
+            PerpExchangeError::InvalidParameter
+        );
+    }
+
+    market.oracle_sources = [OracleSource::Admin; MAX_ORACLE_SOURCES];
+    market.oracle_sources[..params.sources.len()].copy_from_slice(&params.sources);
+    market.oracle_source_count = params.sources.len() as u8;
+    market.source_prices = [SourcePrice::default(); MAX_ORACLE_SOURCES];
+    market.oracle_feed = Pubkey::default();
+    market.oracle_program = Pubkey::default();
+
+    if params.sources.contains(&OracleSource::Pyth) {
+        let price = load_pyth_price(&ctx.accounts.price_feed, &params.oracle_program)?;
+        market.oracle_feed = ctx.accounts.price_feed.key();
+        market.oracle_program = params.oracle_program;
+
+        // Reconfiguring is itself an admin confirmation of the feed's price
+        let mut update = apply_pyth_price(market, &price, clock.unix_timestamp)?;
+        if update == PriceUpdate::Halted {
+            let pending_price = market.pending_price;
+            market.oracle_price_source = OracleSource::Pyth;
+            market.resume_trading(pending_price, price.publish_time);
+            update = PriceUpdate::Applied;
+        }
+        log_price_update(market, &mut ctx.accounts.price_history, update);
+    }
+
+    msg!(
+        "Oracle sources for market {} set to {:?} - Price: {}, Exponent: {}",
+        market.market_index,
+        market.oracle_sources(),
+        market.oracle_price,
+        market.price_exponent
+    );
+    Ok(())
+}
+
+/// Pull the latest price from a market's price account (permissionless)
+#[derive(Accounts)]
+pub struct RefreshOraclePrice<'info> {
+    #[account(
+        mut,
+        seeds = [MARKET_SEED, &market.market_index.to_le_bytes()],
+        bump = market.bump
+    )]
+    pub market: Account<'info, Market>,
+
+    #[account(
+        mut,
+        seeds = [PRICE_HISTORY_SEED, &market.market_index.to_le_bytes()],
+        bump
+    )]
+    pub price_history: Box<Account<'info, PriceHistory>>,
+
+    /// CHECK: Must be the market's configured feed; owner and layout are validated when read
+    #[account(address = market.oracle_feed @ PerpExchangeError::InvalidOracle)]
+    pub price_feed: AccountInfo<'info>,
+}
+
+pub fn handle_refresh_oracle_price(ctx: Context<RefreshOraclePrice>) -> Result<()> {
+    let market = &mut ctx.accounts.market;
+    let clock = Clock::get()?;
+
+    let price = load_pyth_price(&ctx.accounts.price_feed, &market.oracle_program)?;
+    let update = apply_pyth_price(market, &price, clock.unix_timestamp)?;
+    log_price_update(market, &mut ctx.accounts.price_history, update);
+    Ok(())
+}
+
+/// Re-select the index price from the market's oracle sources, e.g. after the
+/// preferred source went stale (permissionless)
+#[derive(Accounts)]
+pub struct SelectOracleSource<'info> {
+    #[account(
+        mut,
+        seeds = [MARKET_SEED, &market.market_index.to_le_bytes()],
+        bump = market.bump
+    )]
+    pub market: Account<'info, Market>,
+
+    #[account(
+        mut,
+        seeds = [PRICE_HISTORY_SEED, &market.market_index.to_le_bytes()],
+        bump
+    )]
+    pub price_history: Box<Account<'info, PriceHistory>>,
+}
+
+pub fn handle_select_oracle_source(ctx: Context<SelectOracleSource>) -> Result<()> {
+    let market = &mut ctx.accounts.market;
+    let clock = Clock::get()?;
+
+    let update = market.select_oracle_price(clock.unix_timestamp);
+    log_price_update(market, &mut ctx.accounts.price_history, update);
+    Ok(())
+}
+
+/// Create the publisher set of a market (admin only)
+#[derive(Accounts)]
+pub struct CreatePublisherSet<'info> {
+    #[account(
+        seeds = [EXCHANGE_STATE_SEED],
+        bump,
+        constraint = exchange_state.admin == admin.key() @ PerpExchangeError::UnauthorizedAdmin
+    )]
+    pub exchange_state: Account<'info, ExchangeState>,
+
+    #[account(
+        seeds = [MARKET_SEED, &market.market_index.to_le_bytes()],
+        bump = market.bump
+    )]
+    pub market: Account<'info, Market>,
+
+    #[account(
+        init,
+        payer = admin,
+        space = PublisherSet::SPACE,
+        seeds = [PUBLISHER_SET_SEED, &market.market_index.to_le_bytes()],
+        bump
+    )]
+    pub publisher_set: Account<'info, PublisherSet>,
+
+    #[account(mut)]
+    pub admin: Signer<'info>,
+
+    pub system_program: Program<'info, System>,
+}
+
+/// Quorum and heartbeat of a publisher set
+#[derive(AnchorSerialize, AnchorDeserialize)]
+pub struct PublisherSetParams {
+    pub quorum: u8,
+    pub heartbeat: u32,
+}
+
+pub fn handle_create_publisher_set(
+    ctx: Context<CreatePublisherSet>,
+    params: PublisherSetParams,
+) -> Result<()> {
+    let publisher_set = &mut ctx.accounts.publisher_set;
+
+    publisher_set.market_index = ctx.accounts.market.market_index;
+    publisher_set.quorum = params.quorum;
+    publisher_set.heartbeat = params.heartbeat;
+    publisher_set.publisher_count = 0;
+    publisher_set.submissions = Default::default();
+    publisher_set.validate()?;
+
+    msg!(
+        "Publisher set created for market {} - Quorum: {}, Heartbeat: {}",
+        publisher_set.market_index,
+        params.quorum,
+        params.heartbeat
+    );
+    Ok(())
+}
+
+/// Change the publishers, quorum or heartbeat of a publisher set (admin only)
+#[derive(Accounts)]
+pub struct ManagePublisherSet<'info> {
+    #[account(
+        seeds = [EXCHANGE_STATE_SEED],
+        bump,
+        constraint = exchange_state.admin == admin.key() @ PerpExchangeError::UnauthorizedAdmin
+    )]
+    pub exchange_state: Account<'info, ExchangeState>,
+
+    #[account(
+        mut,
+        seeds = [PUBLISHER_SET_SEED, &publisher_set.market_index.to_le_bytes()],
+        bump
+    )]
+    pub publisher_set: Account<'info, PublisherSet>,
+
+    pub admin: Signer<'info>,
+}
+
+pub fn handle_add_publisher(ctx: Context<ManagePublisherSet>, publisher: Pubkey) -> Result<()> {
+    let publisher_set = &mut ctx.accounts.publisher_set;
+
+    require!(
+        publisher_set.publisher_index(&publisher).is_err(),
+        PerpExchangeError::InvalidParameter
+    );
+    let count = publisher_set.publisher_count as usize;
+    require!(count < MAX_PUBLISHERS, PerpExchangeError::TooManyPublishers);
+
+    publisher_set.submissions[count] = PriceSubmission {
+        publisher,
+        price: 0,
+        submitted_at: 0,
+    };
+    publisher_set.publisher_count += 1;
+
+    msg!("Publisher {} added to market {}", publisher, publisher_set.market_index);
+    Ok(())
+}
+
+pub fn handle_remove_publisher(ctx: Context<ManagePublisherSet>, publisher: Pubkey) -> Result<()> {
+    let publisher_set = &mut ctx.accounts.publisher_set;
+
+    // Move the last publisher into the freed slot
+    let index = publisher_set.publisher_index(&publisher)?;
+    let last = publisher_set.publisher_count as usize - 1;
+    publisher_set.submissions[index] = publisher_set.submissions[last];
+    publisher_set.submissions[last] = PriceSubmission::default();
+    publisher_set.publisher_count -= 1;
+
+    msg!("Publisher {} removed from market {}", publisher, publisher_set.market_index);
+    Ok(())
+}
+
+pub fn handle_configure_publisher_set(
+    ctx: Context<ManagePublisherSet>,
+    params: PublisherSetParams,
+) -> Result<()> {
+    let publisher_set = &mut ctx.accounts.publisher_set;
+
+    publisher_set.quorum = params.quorum;
+    publisher_set.heartbeat = params.heartbeat;
+    publisher_set.validate()?;
+
+    msg!(
+        "Publisher set for market {} updated - Quorum: {}, Heartbeat: {}",
+        publisher_set.market_index,
+        params.quorum,
+        params.heartbeat
+    );
+    Ok(())
+}
+
+/// Post a price as one of a market's publishers
+#[derive(Accounts)]
+pub struct SubmitPrice<'info> {
+    #[account(
+        mut,
+        seeds = [MARKET_SEED, &market.market_index.to_le_bytes()],
+        bump = market.bump
+    )]
+    pub market: Account<'info, Market>,
+
+    #[account(
+        mut,
+        seeds = [PUBLISHER_SET_SEED, &market.market_index.to_le_bytes()],
+        bump
+    )]
+    pub publisher_set: Account<'info, PublisherSet>,
+
+    #[account(
+        mut,
+        seeds = [PRICE_HISTORY_SEED, &market.market_index.to_le_bytes()],
+        bump
+    )]
+    pub price_history: Box<Account<'info, PriceHistory>>,
+
+    pub publisher: Signer<'info>,
+}
+
+pub fn handle_submit_price(ctx: Context<SubmitPrice>, price: u64) -> Result<()> {
+    let market = &mut ctx.accounts.market;
+    let publisher_set = &mut ctx.accounts.publisher_set;
+    let clock = Clock::get()?;
+
+    require!(price > 0, PerpExchangeError::InvalidPrice);
+
+    // Record the submission
+    let index = publisher_set.publisher_index(&ctx.accounts.publisher.key())?;
+    publisher_set.submissions[index].price = price;
+    publisher_set.submissions[index].submitted_at = clock.unix_timestamp;
+
+    // Publishers past their heartbeat drop out of the median
+    let Some(median) = publisher_set.median_price(clock.unix_timestamp) else {
+        msg!("Price recorded for market {}, waiting for quorum", market.market_index);
+        return Ok(());
+    };
+
+    let median = market.normalize_price(median)?;
+    let update = market.record_source_price(
+        OracleSource::Publishers,
+        SourcePrice {
+            price: median,
+            confidence: 0,
+            timestamp: clock.unix_timestamp,
+            reporter: ctx.accounts.publisher.key(),
+        },
+        clock.unix_timestamp,
+    )?;
+    log_price_update(market, &mut ctx.accounts.price_history, update);
+    Ok(())
+}
+
+/// Read-only view of a market's price history
+#[derive(Accounts)]
+pub struct ViewPriceHistory<'info> {
+    #[account(
+        seeds = [PRICE_HISTORY_SEED, &price_history.market_index.to_le_bytes()],
+        bump
+    )]
+    pub price_history: Box<Account<'info, PriceHistory>>,
+}
+
+/// Time-weighted average index price over the last `window` seconds
+pub fn handle_twap_price(ctx: Context<ViewPriceHistory>, window: u32) -> Result<Price> {
+    let clock = Clock::get()?;
+    ctx.accounts.price_history.twap(clock.unix_timestamp, window)
+}
@@ -0,0 +1,526 @@
+use anchor_lang::prelude::*;
+use crate::state::{ExchangeState, Market, UserAccount, VaultAccount, Order, OrderBook};
+use crate::accounting::{apply_fill, increase_position, PositionAccounts};
+use crate::constants::*;
+use crate::error::PerpExchangeError;
+use crate::price::Price;
+
+/// Attach a pre-allocated order book slab to a market (admin only)
+#[derive(Accounts)]
+pub struct InitializeOrderBook<'info> {
+    #[account(
+        seeds = [EXCHANGE_STATE_SEED],
+        bump,
+        constraint = exchange_state.admin == admin.key() @ PerpExchangeError::UnauthorizedAdmin
+    )]
+    pub exchange_state: Account<'info, ExchangeState>,
+
+    #[account(
+        seeds = [MARKET_SEED, &market.market_index.to_le_bytes()],
+        bump = market.bump
+    )]
+    pub market: Account<'info, Market>,
+
+    /// Allocated by the client with `OrderBook::SPACE` bytes, owned by this program
+    #[account(zero)]
+    pub order_book: AccountLoader<'info, OrderBook>,
+
+    pub admin: Signer<'info>,
+}
+
+pub fn handle_initialize_order_book(ctx: Context<InitializeOrderBook>) -> Result<()> {
+    let market = &ctx.accounts.market;
+    let mut order_book = ctx.accounts.order_book.load_init()?;
+
+    order_book.market = market.key();
+    order_book.market_index = market.market_index;
+    order_book.next_order_id = 0;
+
+    msg!("Order book initialized for market {}", market.market_index);
+    Ok(())
+}
+
+/// Place or cancel a resting limit order
+#[derive(Accounts)]
+pub struct ManageOrder<'info> {
+    #[account(
+        seeds = [MARKET_SEED, &market.market_index.to_le_bytes()],
+        bump = market.bump
+    )]
+    pub market: Account<'info, Market>,
+
+    #[account(
+        mut,
+        constraint = order_book.load()?.market == market.key() @ PerpExchangeError::InvalidOrderBook
+    )]
+    pub order_book: AccountLoader<'info, OrderBook>,
+
+    #[account(
+        mut,
+        seeds = [USER_ACCOUNT_SEED, user.key().as_ref()],
+        bump,
+        constraint = user_account.owner == user.key() @ PerpExchangeError::UnauthorizedUser
+    )]
+    pub user_account: Account<'info, UserAccount>,
+
+    pub user: Signer<'info>,
+}
+
+/// Parameters for placing a limit order
+#[derive(AnchorSerialize, AnchorDeserialize)]
+pub struct PlaceLimitOrderParams {
+    pub is_long: bool,
+    /// Limit price
+    pub price: Price,
+    pub margin: u64,
+    pub leverage: u8,
+}
+
+pub fn handle_place_limit_order(
+    ctx: Context<ManageOrder>,
+    params: PlaceLimitOrderParams,
+) -> Result<()> {
+    let market = &ctx.accounts.market;
+    let user_account = &mut ctx.accounts.user_account;
+    let mut order_book = ctx.accounts.order_book.load_mut()?;
+    let clock = Clock::get()?;
+
+    // Validate inputs with the same rules as open_position
+    market.check_not_halted()?;
+    require!(!params.price.is_zero(), PerpExchangeError::InvalidPrice);
+    require!(params.margin > 0, PerpExchangeError::InvalidAmount);
+    require!(
+        params.leverage > 0 && params.leverage <= market.governance_params.max_leverage,
+        PerpExchangeError::InvalidLeverage
+    );
+    require!(
+        params.margin >= market.governance_params.min_margin,
+        PerpExchangeError::MarginTooLow
+    );
+    require!(
+        !order_book.crosses_own_order(&user_account.owner, params.is_long, params.price),
+        PerpExchangeError::SelfTrade
+    );
+
+    // Lock margin and the trading fee until the order fills or is cancelled
+    let size = params.margin
+        .checked_mul(params.leverage as u64)
+        .ok_or(PerpExchangeError::MathOverflow)?;
+    require!(
+        params.margin >= market.initial_margin_requirement(size),
+        PerpExchangeError::InitialMarginNotMet
+    );
+    let reserved = params.margin
+        .checked_add(market.trading_fee(params.margin)?)
+        .ok_or(PerpExchangeError::MathOverflow)?;
+    user_account.collateral_balance = user_account.collateral_balance
+        .checked_sub(reserved)
+        .ok_or(PerpExchangeError::InsufficientCollateral)?;
+
+    let order_id = order_book.next_order_id;
+    order_book.insert(
+        params.is_long,
+        Order {
+            owner: user_account.owner,
+            order_id,
+            price: params.price,
+            size,
+            margin: params.margin,
+            reserved,
+            placed_at: clock.unix_timestamp,
+            leverage: params.leverage,
+            _padding: [0; 7],
+        },
+    )?;
+    order_book.next_order_id = order_id
+        .checked_add(1)
+        .ok_or(PerpExchangeError::MathOverflow)?;
+
+    msg!(
+        "Limit order placed - User: {}, Market: {}, Order: {}, Long: {}, Price: {}, Size: {}",
+        user_account.owner,
+        market.market_index,
+        order_id,
+        params.is_long,
+        params.price,
+        size
+    );
+    Ok(())
+}
+
+pub fn handle_cancel_order(ctx: Context<ManageOrder>, order_id: u64) -> Result<()> {
+    let user_account = &mut ctx.accounts.user_account;
+    let mut order_book = ctx.accounts.order_book.load_mut()?;
+
+    let (is_bid, index) = order_book
+        .find(order_id)
+        .ok_or(PerpExchangeError::OrderNotFound)?;
+    require!(
+        order_book.orders(is_bid)[index].owner == user_account.owner,
+        PerpExchangeError::UnauthorizedUser
+    );
+
+    // Release the locked collateral
+    let order = order_book.remove(is_bid, index);
+    user_account.collateral_balance = user_account.collateral_balance
+        .checked_add(order.reserved)
+        .ok_or(PerpExchangeError::MathOverflow)?;
+
+    msg!("Order {} cancelled - User: {}", order_id, user_account.owner);
+    Ok(())
+}
+
+/// Match the best bid against the best ask (permissionless crank)
+#[derive(Accounts)]
+pub struct MatchOrders<'info> {
+    #[account(
+        mut,
+        seeds = [EXCHANGE_STATE_SEED],
+        bump
+    )]
+    pub exchange_state: Account<'info, ExchangeState>,
+
+    #[account(
+        mut,
+        seeds = [MARKET_SEED, &market.market_index.to_le_bytes()],
+        bump = market.bump
+    )]
+    pub market: Account<'info, Market>,
+
+    #[account(
+        mut,
+        constraint = order_book.load()?.market == market.key() @ PerpExchangeError::InvalidOrderBook
+    )]
+    pub order_book: AccountLoader<'info, OrderBook>,
+
+    #[account(
+        mut,
+        seeds = [VAULT_SEED],
+        bump
+    )]
+    pub vault: Account<'info, VaultAccount>,
+
+    /// User account of the best bid's owner
+    #[account(
+        mut,
+        seeds = [USER_ACCOUNT_SEED, bid_user_account.owner.as_ref()],
+        bump
+    )]
+    pub bid_user_account: Account<'info, UserAccount>,
+
+    /// User account of the best ask's owner
+    #[account(
+        mut,
+        seeds = [USER_ACCOUNT_SEED, ask_user_account.owner.as_ref()],
+        bump
+    )]
+    pub ask_user_account: Account<'info, UserAccount>,
+
+    pub cranker: Signer<'info>,
+}
+
+/// Portion of a resting order consumed by a fill
+struct OrderFill {
+    margin: u64,
+    reserved: u64,
+}
+
+impl OrderFill {
+    fn new(order: &Order, fill_size: u64) -> Self {
+        if fill_size == order.size {
+            return Self { margin: order.margin, reserved: order.reserved };
+        }
+        let pro_rata = |amount: u64| {
+            ((amount as u128) * (fill_size as u128) / (order.size as u128)) as u64
+        };
+        Self { margin: pro_rata(order.margin), reserved: pro_rata(order.reserved) }
+    }
+
+    /// Shrink the best order on the given side by this fill of `fill_size`, or
+    /// remove it once it is filled in full
+    fn consume(&self, order_book: &mut OrderBook, is_bid: bool, fill_size: u64) {
+        let order = order_book.order_mut(is_bid, 0);
+        if order.size == fill_size {
+            order_book.remove(is_bid, 0);
+        } else {
+            order.size -= fill_size;
+            order.margin -= self.margin;
+            order.reserved -= self.reserved;
+        }
+    }
+}
+
+/// Remove the best order on the given side and release its locked collateral
+/// to its owner
+fn drop_best_order(order_book: &mut OrderBook, is_bid: bool, user_account: &mut UserAccount) -> Result<Order> {
+    let order = order_book.remove(is_bid, 0);
+    user_account.collateral_balance = user_account.collateral_balance
+        .checked_add(order.reserved)
+        .ok_or(PerpExchangeError::MathOverflow)?;
+    Ok(order)
+}
+
+/// Whether the owner of an order can take `fill` of `fill_size` at `fill_price`:
+/// they have the collateral for its margin and fee, and the position it lands in
+/// meets the initial margin at the mark price afterwards
+fn can_take_fill(
+    market: &Market,
+    user_account: &UserAccount,
+    is_bid: bool,
+    fill: &OrderFill,
+    fill_size: u64,
+    fill_price: Price,
+    now: i64,
+) -> Result<bool> {
+    let fee = market.trading_fee(fill.margin)?;
+    let available = user_account.collateral_balance.saturating_add(fill.reserved);
+    let margined = match user_account.fill_position_index(market.market_index, is_bid) {
+        Some(position_index) if fill.margin > 0 => {
+            let mut position = user_account.positions[position_index].clone();
+            apply_fill(market, &mut position, is_bid, fill.margin, fill_size, fill_price, now)?;
+            market.check_initial_margin(&position).is_ok()
+        }
+        _ => false,
+    };
+    Ok(margined && available >= fill.margin.saturating_add(fee))
+}
+
+pub fn handle_match_orders(ctx: Context<MatchOrders>) -> Result<()> {
+    let clock = Clock::get()?;
+    let market_index = ctx.accounts.market.market_index;
+    ctx.accounts.market.check_not_halted()?;
+    ctx.accounts.market.check_oracle_fresh(clock.unix_timestamp)?;
+
+    let (bid, ask) = {
+        let order_book = ctx.accounts.order_book.load()?;
+        (
+            order_book.best_bid().ok_or(PerpExchangeError::OrdersNotCrossing)?,
+            order_book.best_ask().ok_or(PerpExchangeError::OrdersNotCrossing)?,
+        )
+    };
+    require!(bid.price >= ask.price, PerpExchangeError::OrdersNotCrossing);
+    require!(
+        ctx.accounts.bid_user_account.owner == bid.owner
+            && ctx.accounts.ask_user_account.owner == ask.owner,
+        PerpExchangeError::UnauthorizedUser
+    );
+
+    // The older (resting) order sets the price. A resting price too far from the
+    // index would book instant P&L against the pool, so that order is dropped
+    // and refunded instead of filled.
+    let bid_is_older = bid.order_id < ask.order_id;
+    let fill_price = if bid_is_older { bid.price } else { ask.price };
+    if !ctx.accounts.market.within_fill_band(fill_price) {
+        let (is_bid, user_account) = if bid_is_older {
+            (true, &mut ctx.accounts.bid_user_account)
+        } else {
+            (false, &mut ctx.accounts.ask_user_account)
+        };
+        let order = drop_best_order(&mut *ctx.accounts.order_book.load_mut()?, is_bid, user_account)?;
+        msg!("Order {} dropped - price {} is outside the fill band", order.order_id, fill_price);
+        return Ok(());
+    }
+
+    let fill_size = bid.size.min(ask.size);
+    let bid_fill = OrderFill::new(&bid, fill_size);
+    let ask_fill = OrderFill::new(&ask, fill_size);
+
+    // An order whose owner can no longer take the fill, or whose position would
+    // not meet the initial margin at the mark price after it, is dropped and
+    // refunded so it cannot block the book
+    for (is_bid, fill, user_account) in [
+        (true, &bid_fill, &mut ctx.accounts.bid_user_account),
+        (false, &ask_fill, &mut ctx.accounts.ask_user_account),
+    ] {
+        let fillable = can_take_fill(
+            &ctx.accounts.market,
+            user_account,
+            is_bid,
+            fill,
+            fill_size,
+            fill_price,
+            clock.unix_timestamp,
+        )?;
+        if !fillable {
+            let order = drop_best_order(&mut *ctx.accounts.order_book.load_mut()?, is_bid, user_account)?;
+            msg!("Order {} dropped - owner cannot take the fill", order.order_id);
+            return Ok(());
+        }
+    }
+
+    // Fill both sides through the same accounting as open_position
+    for (is_bid, fill, user_account) in [
+        (true, &bid_fill, &mut ctx.accounts.bid_user_account),
+        (false, &ask_fill, &mut ctx.accounts.ask_user_account),
+    ] {
+        user_account.collateral_balance = user_account.collateral_balance
+            .checked_add(fill.reserved)
+            .ok_or(PerpExchangeError::MathOverflow)?;
+
+        let position_index = user_account
+            .fill_position_index(market_index, is_bid)
+            .ok_or(PerpExchangeError::TooManyPositions)?;
+        increase_position(
+            PositionAccounts {
+                exchange_state: &mut ctx.accounts.exchange_state,
+                market: &mut ctx.accounts.market,
+                vault: &mut ctx.accounts.vault,
+                user_account,
+            },
+            position_index,
+            is_bid,
+            fill.margin,
+            fill_size,
+            fill_price,
+            clock.unix_timestamp,
+        )?;
+    }
+
+    // Shrink or remove the filled orders
+    let mut order_book = ctx.accounts.order_book.load_mut()?;
+    bid_fill.consume(&mut order_book, true, fill_size);
+    ask_fill.consume(&mut order_book, false, fill_size);
+
+    msg!(
+        "Orders matched - Market: {}, Bid: {}, Ask: {}, Price: {}, Size: {}",
+        market_index,
+        bid.order_id,
+        ask.order_id,
+        fill_price,
+        fill_size
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::{GovernanceParams, Position};
+
+    const ONE: Price = Price::new(PRICE_PRECISION);
+    const NOW: i64 = 1_000;
+
+    fn market() -> Market {
+        Market {
+            oracle_price: ONE,
+            mark_price_ema: ONE,
+            governance_params: GovernanceParams {
+                trading_fee_rate: 100,
+                initial_margin_rate: 1000,
+                maintenance_margin_rate: 500,
+                max_fill_deviation_rate: 500,
+                ..Default::default()
+            },
+            ..Default::default()
+        }
+    }
+
+    fn resting_order(order_id: u64) -> Order {
+        Order {
+            owner: Pubkey::new_unique(),
+            order_id,
+            price: ONE,
+            size: 1_000,
+            margin: 100,
+            reserved: 101,
+            ..Default::default()
+        }
+    }
+
+    fn user_account(collateral_balance: u64) -> UserAccount {
+        UserAccount { collateral_balance, ..Default::default() }
+    }
+
+    #[test]
+    fn partial_fill_takes_margin_and_reserve_pro_rata() {
+        let order = resting_order(0);
+        let fill = OrderFill::new(&order, 300);
+        assert_eq!((fill.margin, fill.reserved), (30, 30));
+
+        let fill = OrderFill::new(&order, order.size);
+        assert_eq!((fill.margin, fill.reserved), (100, 101));
+    }
+
+    #[test]
+    fn fills_shrink_the_best_order_until_it_is_removed() {
+        let mut order_book: OrderBook = bytemuck::Zeroable::zeroed();
+        order_book.insert(true, resting_order(0)).unwrap();
+        order_book.insert(true, Order { price: Price::new(PRICE_PRECISION - 1), ..resting_order(1) }).unwrap();
+
+        let order = order_book.best_bid().unwrap();
+        OrderFill::new(&order, 300).consume(&mut order_book, true, 300);
+        let order = order_book.best_bid().unwrap();
+        assert_eq!((order.order_id, order.size, order.margin, order.reserved), (0, 700, 70, 71));
+
+        // The last fill takes whatever is left, rounding dust included
+        let fill = OrderFill::new(&order, 700);
+        assert_eq!((fill.margin, fill.reserved), (70, 71));
+        fill.consume(&mut order_book, true, 700);
+        assert_eq!(order_book.bid_count, 1);
+        assert_eq!(order_book.best_bid().unwrap().order_id, 1);
+    }
+
+    #[test]
+    fn dropped_order_is_removed_and_refunded() {
+        let mut order_book: OrderBook = bytemuck::Zeroable::zeroed();
+        order_book.insert(false, resting_order(0)).unwrap();
+        order_book.insert(false, Order { price: Price::new(PRICE_PRECISION + 1), ..resting_order(1) }).unwrap();
+        let mut owner = user_account(5);
+
+        let dropped = drop_best_order(&mut order_book, false, &mut owner).unwrap();
+        assert_eq!(dropped.order_id, 0);
+        assert_eq!(owner.collateral_balance, 106);
+        assert_eq!(order_book.ask_count, 1);
+        assert_eq!(order_book.best_ask().unwrap().order_id, 1);
+    }
+
+    #[test]
+    fn resting_price_outside_the_fill_band_is_not_filled() {
+        let market = market();
+        assert!(market.within_fill_band(Price::new(PRICE_PRECISION * 105 / 100)));
+        assert!(market.within_fill_band(Price::new(PRICE_PRECISION * 95 / 100)));
+        assert!(!market.within_fill_band(Price::new(PRICE_PRECISION * 105 / 100 + 1)));
+        assert!(!market.within_fill_band(Price::new(PRICE_PRECISION * 95 / 100 - 1)));
+    }
+
+    #[test]
+    fn fill_needs_collateral_and_initial_margin() {
+        let market = market();
+        let order = resting_order(0);
+        let fill = OrderFill::new(&order, order.size);
+
+        // The reserve covers margin and fee
+        assert!(can_take_fill(&market, &user_account(0), true, &fill, order.size, ONE, NOW).unwrap());
+        // Without it, free collateral must
+        let unreserved = OrderFill { reserved: 0, ..fill };
+        assert!(!can_take_fill(&market, &user_account(100), true, &unreserved, order.size, ONE, NOW).unwrap());
+        assert!(can_take_fill(&market, &user_account(101), true, &unreserved, order.size, ONE, NOW).unwrap());
+
+        // A price that books an instant loss leaves the position below initial margin
+        let rich = Price::new(PRICE_PRECISION * 102 / 100);
+        assert!(!can_take_fill(&market, &user_account(0), true, &fill, order.size, rich, NOW).unwrap());
+        assert!(can_take_fill(&market, &user_account(0), false, &fill, order.size, rich, NOW).unwrap());
+
+        // Nothing left to margin the fill with
+        let dust = OrderFill { margin: 0, reserved: 0 };
+        assert!(!can_take_fill(&market, &user_account(1_000), true, &dust, 1, ONE, NOW).unwrap());
+    }
+
+    #[test]
+    fn fill_against_an_opposite_position_is_not_taken() {
+        let market = market();
+        let order = resting_order(0);
+        let fill = OrderFill::new(&order, order.size);
+        let mut owner = user_account(1_000);
+        owner.positions[0] = Position {
+            size: -1_000,
+            margin: 100,
+            entry_price: ONE,
+            is_open: true,
+            ..Default::default()
+        };
+
+        assert!(!can_take_fill(&market, &owner, true, &fill, order.size, ONE, NOW).unwrap());
+        assert!(can_take_fill(&market, &owner, false, &fill, order.size, ONE, NOW).unwrap());
+    }
+}
@@ -1,10 +1,15 @@
 use anchor_lang::prelude::*;
-use crate::state::{ExchangeState, UserAccount, VaultAccount, Position};
+use anchor_spl::token::{Mint, Token, TokenAccount};
+use crate::state::{ExchangeState, Market, Position, UserAccount, VaultAccount, InsuranceFund};
+use crate::accounting::{decrease_position, increase_position, settle_funding, PositionAccounts};
 use crate::constants::*;
 use crate::error::PerpExchangeError;
+use crate::price::Price;
+use crate::utils::transfer_from_vault;
 
 /// Open a perpetual position
 #[derive(Accounts)]
+#[instruction(params: OpenPositionParams)]
 pub struct OpenPosition<'info> {
     #[account(
         mut,
@@ -13,6 +18,13 @@ pub struct OpenPosition<'info> {
     )]
     pub exchange_state: Account<'info, ExchangeState>,
 
+    #[account(
+        mut,
+        seeds = [MARKET_SEED, &params.market_index.to_le_bytes()],
+        bump = market.bump
+    )]
+    pub market: Account<'info, Market>,
+
     #[account(
         mut,
         seeds = [USER_ACCOUNT_SEED, user.key().as_ref()],
@@ -35,133 +47,113 @@ pub struct OpenPosition<'info> {
 /// Parameters for opening a position
 #[derive(AnchorSerialize, AnchorDeserialize)]
 pub struct OpenPositionParams {
+    pub market_index: u16,
     pub is_long: bool,
     pub margin: u64,
     pub leverage: u8,
+    /// Highest acceptable fill price
+    pub max_price: Option<Price>,
+    /// Lowest acceptable fill price
+    pub min_price: Option<Price>,
+    /// Unix timestamp after which the order must not execute
+    pub deadline: Option<i64>,
+}
+
+/// Check a fill price and the current time against optional execution bounds
+pub fn check_execution_limits(
+    price: Price,
+    min_price: Option<Price>,
+    max_price: Option<Price>,
+    deadline: Option<i64>,
+    now: i64,
+) -> Result<()> {
+    if let Some(deadline) = deadline {
+        require!(now <= deadline, PerpExchangeError::DeadlineExceeded);
+    }
+    if let Some(max_price) = max_price {
+        require!(price <= max_price, PerpExchangeError::SlippageExceeded);
+    }
+    if let Some(min_price) = min_price {
+        require!(price >= min_price, PerpExchangeError::SlippageExceeded);
+    }
+    Ok(())
 }
 
-pub fn open_position(
+pub fn handle_open_position(
     ctx: Context<OpenPosition>,
     params: OpenPositionParams,
 ) -> Result<()> {
-    let exchange_state = &mut ctx.accounts.exchange_state;
-    let user_account = &mut ctx.accounts.user_account;
-    let vault = &mut ctx.accounts.vault;
+    let market = &ctx.accounts.market;
+    let user_account = &ctx.accounts.user_account;
     let clock = Clock::get()?;
 
     // Validate inputs
     require!(params.margin > 0, PerpExchangeError::InvalidAmount);
     require!(
-        params.leverage > 0 && params.leverage <= exchange_state.governance_params.max_leverage,
+        params.leverage > 0 && params.leverage <= market.governance_params.max_leverage,
         PerpExchangeError::InvalidLeverage
     );
     require!(
-        params.margin >= exchange_state.governance_params.min_margin,
+        params.margin >= market.governance_params.min_margin,
         PerpExchangeError::MarginTooLow
     );
 
-    // Check user doesn't have existing position
-    require!(!user_account.position.is_open, PerpExchangeError::PositionExists);
+    // Check user doesn't have existing position on this market and has a free slot
+    let position_index = user_account.free_position_index(market.market_index)?;
 
-    // Check user has sufficient collateral
-    require!(
-        user_account.collateral_balance >= params.margin,
-        PerpExchangeError::InsufficientCollateral
-    );
-
-    // Check oracle price is fresh
-    let oracle_age = clock.unix_timestamp - exchange_state.oracle_last_update;
-    require!(
-        oracle_age <= exchange_state.governance_params.oracle_validity_period as i64,
-        PerpExchangeError::StaleOracle
-    );
+    // Check the market is not halted and the oracle price is fresh
+    market.check_not_halted()?;
+    market.check_oracle_fresh(clock.unix_timestamp)?;
 
     // Calculate position size
-    let position_size = (params.margin as u128)
-        .checked_mul(params.leverage as u128)
-        .ok_or(PerpExchangeError::MathOverflow)?;
-    
-    let signed_size = if params.is_long {
-        position_size as i64
-    } else {
-        -(position_size as i64)
-    };
-
-    // Calculate trading fee
-    let trading_fee = (params.margin as u128)
-        .checked_mul(exchange_state.governance_params.trading_fee_rate as u128)
-        .ok_or(PerpExchangeError::MathOverflow)?
-        .checked_div(10000)
-        .ok_or(PerpExchangeError::MathOverflow)? as u64;
-
-    // Check user has enough collateral including fees
-    let total_required = params.margin
-        .checked_add(trading_fee)
+    let position_size = params.margin
+        .checked_mul(params.leverage as u64)
         .ok_or(PerpExchangeError::MathOverflow)?;
-    
     require!(
-        user_account.collateral_balance >= total_required,
-        PerpExchangeError::InsufficientCollateral
+        params.margin >= market.initial_margin_requirement(position_size),
+        PerpExchangeError::InitialMarginNotMet
     );
-
-    // Create position
-    user_account.position = Position {
-        size: signed_size,
-        margin: params.margin,
-        entry_price: exchange_state.oracle_price,
-        is_open: true,
-        leverage: params.leverage,
-        opened_at: clock.unix_timestamp,
-    };
-
-    // Deduct margin and fees from user balance
-    user_account.collateral_balance = user_account.collateral_balance
-        .checked_sub(total_required)
-        .ok_or(PerpExchangeError::MathOverflow)?;
-    
-    user_account.total_fees_paid = user_account.total_fees_paid
-        .checked_add(trading_fee)
-        .ok_or(PerpExchangeError::MathOverflow)?;
-
-    // Update vault reserved collateral
-    vault.reserved_collateral = vault.reserved_collateral
-        .checked_add(params.margin)
-        .ok_or(PerpExchangeError::MathOverflow)?;
-
-    // Update exchange statistics
-    exchange_state.collected_fees = exchange_state.collected_fees
-        .checked_add(trading_fee)
-        .ok_or(PerpExchangeError::MathOverflow)?;
-    
-    if params.is_long {
-        exchange_state.total_long_positions = exchange_state.total_long_positions
-            .checked_add(position_size as u64)
-            .ok_or(PerpExchangeError::MathOverflow)?;
-    } else {
-        exchange_state.total_short_positions = exchange_state.total_short_positions
-            .checked_add(position_size as u64)
-            .ok_or(PerpExchangeError::MathOverflow)?;
-    }
-
-    let notional_value = position_size as u64;
-    exchange_state.total_volume = exchange_state.total_volume
-        .checked_add(notional_value)
-        .ok_or(PerpExchangeError::MathOverflow)?;
+    let entry_price = ctx.accounts.market.fill_price(params.is_long, position_size)?;
+    check_execution_limits(
+        entry_price,
+        params.min_price,
+        params.max_price,
+        params.deadline,
+        clock.unix_timestamp,
+    )?;
+
+    let trading_fee = increase_position(
+        PositionAccounts {
+            exchange_state: &mut ctx.accounts.exchange_state,
+            market: &mut ctx.accounts.market,
+            vault: &mut ctx.accounts.vault,
+            user_account: &mut ctx.accounts.user_account,
+        },
+        position_index,
+        params.is_long,
+        params.margin,
+        position_size,
+        entry_price,
+        clock.unix_timestamp,
+    )?;
 
     msg!(
-        "Position opened - User: {}, Size: {}, Margin: {}, Price: {}",
+        "Position opened - User: {}, Market: {}, Size: {}, Margin: {}, Price: {}, Fee: {}, Source: {:?}",
         ctx.accounts.user.key(),
-        signed_size,
+        ctx.accounts.market.market_index,
+        ctx.accounts.user_account.positions[position_index].size,
         params.margin,
-        exchange_state.oracle_price
+        entry_price,
+        trading_fee,
+        ctx.accounts.market.oracle_price_source
     );
 
     Ok(())
 }
 
-/// Close a perpetual position
+/// Increase, reduce or close an existing position
 #[derive(Accounts)]
-pub struct ClosePosition<'info> {
+pub struct ModifyPosition<'info> {
     #[account(
         mut,
         seeds = [EXCHANGE_STATE_SEED],
@@ -169,6 +161,13 @@ pub struct ClosePosition<'info> {
     )]
     pub exchange_state: Account<'info, ExchangeState>,
 
+    #[account(
+        mut,
+        seeds = [MARKET_SEED, &market.market_index.to_le_bytes()],
+        bump = market.bump
+    )]
+    pub market: Account<'info, Market>,
+
     #[account(
         mut,
         seeds = [USER_ACCOUNT_SEED, user.key().as_ref()],
@@ -188,124 +187,298 @@ pub struct ClosePosition<'info> {
     pub user: Signer<'info>,
 }
 
-pub fn close_position(ctx: Context<ClosePosition>) -> Result<()> {
-    let exchange_state = &mut ctx.accounts.exchange_state;
-    let user_account = &mut ctx.accounts.user_account;
-    let vault = &mut ctx.accounts.vault;
-    let clock = Clock::get()?;
+/// Parameters for adding to a position
+#[derive(AnchorSerialize, AnchorDeserialize)]
+pub struct IncreasePositionParams {
+    pub margin: u64,
+    pub leverage: u8,
+}
 
-    // Check user has open position
-    require!(user_account.position.is_open, PerpExchangeError::NoPosition);
+pub fn handle_increase_position(
+    ctx: Context<ModifyPosition>,
+    params: IncreasePositionParams,
+) -> Result<()> {
+    let market = &ctx.accounts.market;
+    let user_account = &ctx.accounts.user_account;
+    let clock = Clock::get()?;
 
-    // Check oracle price is fresh
-    let oracle_age = clock.unix_timestamp - exchange_state.oracle_last_update;
+    // Validate inputs
+    require!(params.margin > 0, PerpExchangeError::InvalidAmount);
     require!(
-        oracle_age <= exchange_state.governance_params.oracle_validity_period as i64,
-        PerpExchangeError::StaleOracle
+        params.leverage > 0 && params.leverage <= market.governance_params.max_leverage,
+        PerpExchangeError::InvalidLeverage
     );
 
-    // Extract position data before borrowing mutably
-    let position_size = user_account.position.size;
-    let position_margin = user_account.position.margin;
-    let position_entry_price = user_account.position.entry_price;
-    let is_long = position_size > 0;
-    let position_abs_size = (position_size.abs()) as u64;
+    // Check user has open position on this market
+    let position_index = user_account.position_index(market.market_index)?;
+    let is_long = user_account.positions[position_index].is_long();
 
-    // Calculate P&L
-    let price_diff = if is_long {
-        exchange_state.oracle_price as i128 - position_entry_price as i128
-    } else {
-        position_entry_price as i128 - exchange_state.oracle_price as i128
-    };
+    // Check the market is not halted and the oracle price is fresh
+    market.check_not_halted()?;
+    market.check_oracle_fresh(clock.unix_timestamp)?;
 
-    let pnl = (position_abs_size as i128)
-        .checked_mul(price_diff)
-        .ok_or(PerpExchangeError::MathOverflow)?
-        .checked_div(position_entry_price as i128)
+    // Calculate added size
+    let added_size = params.margin
+        .checked_mul(params.leverage as u64)
         .ok_or(PerpExchangeError::MathOverflow)?;
 
-    // Calculate trading fee for closing
-    let close_fee = (position_margin as u128)
-        .checked_mul(exchange_state.governance_params.trading_fee_rate as u128)
-        .ok_or(PerpExchangeError::MathOverflow)?
-        .checked_div(10000)
-        .ok_or(PerpExchangeError::MathOverflow)? as u64;
-
-    // Calculate final margin after P&L and fees
-    let margin_with_pnl = (position_margin as i128)
-        .checked_add(pnl)
+    // The grown position must meet the initial margin requirement on its
+    // combined notional, counting its current equity and the added margin
+    let position = &user_account.positions[position_index];
+    let equity = market.position_equity(position)?
+        .checked_add(params.margin as i128)
         .ok_or(PerpExchangeError::MathOverflow)?;
-    
-    let final_margin = margin_with_pnl
-        .checked_sub(close_fee as i128)
+    let notional = position.notional_at(market.mark_price_ema)?
+        .checked_add(added_size)
         .ok_or(PerpExchangeError::MathOverflow)?;
+    require!(
+        equity >= market.initial_margin_requirement(notional) as i128,
+        PerpExchangeError::InitialMarginNotMet
+    );
+    let fill_price = ctx.accounts.market.fill_price(is_long, added_size)?;
+
+    let trading_fee = increase_position(
+        PositionAccounts {
+            exchange_state: &mut ctx.accounts.exchange_state,
+            market: &mut ctx.accounts.market,
+            vault: &mut ctx.accounts.vault,
+            user_account: &mut ctx.accounts.user_account,
+        },
+        position_index,
+        is_long,
+        params.margin,
+        added_size,
+        fill_price,
+        clock.unix_timestamp,
+    )?;
 
-    // Return collateral to user (can be negative if loss exceeds margin)
-    if final_margin > 0 {
-        user_account.collateral_balance = user_account.collateral_balance
-            .checked_add(final_margin as u64)
-            .ok_or(PerpExchangeError::MathOverflow)?;
-    } else {
-        // Position was liquidated with loss exceeding margin
-        // In a real implementation, this would trigger insurance fund usage
-        msg!("Position closed with total loss exceeding margin");
-    }
+    let position = &ctx.accounts.user_account.positions[position_index];
+    msg!(
+        "Position increased - User: {}, Market: {}, Size: {}, Entry price: {}, Fee: {}, Source: {:?}",
+        ctx.accounts.user.key(),
+        ctx.accounts.market.market_index,
+        position.size,
+        position.entry_price,
+        trading_fee,
+        position.price_source
+    );
 
-    // Update vault reserved collateral
-    vault.reserved_collateral = vault.reserved_collateral
-        .checked_sub(position_margin)
-        .ok_or(PerpExchangeError::MathOverflow)?;
+    Ok(())
+}
 
-    // Update exchange statistics
-    exchange_state.collected_fees = exchange_state.collected_fees
-        .checked_add(close_fee)
-        .ok_or(PerpExchangeError::MathOverflow)?;
+pub fn handle_reduce_position(ctx: Context<ModifyPosition>, reduce_size: u64) -> Result<()> {
+    let market = &ctx.accounts.market;
+    let user_account = &ctx.accounts.user_account;
+    let clock = Clock::get()?;
 
-    if is_long {
-        exchange_state.total_long_positions = exchange_state.total_long_positions
-            .checked_sub(position_abs_size)
-            .ok_or(PerpExchangeError::MathOverflow)?;
-    } else {
-        exchange_state.total_short_positions = exchange_state.total_short_positions
-            .checked_sub(position_abs_size)
-            .ok_or(PerpExchangeError::MathOverflow)?;
-    }
+    // Check user has open position on this market
+    let position_index = user_account.position_index(market.market_index)?;
+    let position = &user_account.positions[position_index];
+    require!(
+        reduce_size > 0 && reduce_size <= position.get_abs_size(),
+        PerpExchangeError::InvalidAmount
+    );
+    let (is_long, entry_price) = (position.is_long(), position.entry_price);
+
+    // Check the oracle price is fresh; closes stay open at the held price while the
+    // market is halted, until that price goes stale
+    market.check_oracle_fresh(clock.unix_timestamp)?;
+    let exit_price = ctx.accounts.market.exit_price(is_long, reduce_size, entry_price)?;
+
+    let settlement = decrease_position(
+        PositionAccounts {
+            exchange_state: &mut ctx.accounts.exchange_state,
+            market: &mut ctx.accounts.market,
+            vault: &mut ctx.accounts.vault,
+            user_account: &mut ctx.accounts.user_account,
+        },
+        position_index,
+        reduce_size,
+        exit_price,
+        clock.unix_timestamp,
+    )?;
+
+    msg!(
+        "Position reduced - User: {}, Market: {}, Closed size: {}, P&L: {}, Released margin: {}, Source: {:?}",
+        ctx.accounts.user.key(),
+        ctx.accounts.market.market_index,
+        reduce_size,
+        settlement.pnl,
+        settlement.released_margin,
+        ctx.accounts.market.oracle_price_source
+    );
+
+    Ok(())
+}
+
+/// Parameters for closing a position
+#[derive(AnchorSerialize, AnchorDeserialize)]
+pub struct ClosePositionParams {
+    /// Highest acceptable exit price
+    pub max_price: Option<Price>,
+    /// Lowest acceptable exit price
+    pub min_price: Option<Price>,
+    /// Unix timestamp after which the close must not execute
+    pub deadline: Option<i64>,
+}
+
+pub fn handle_close_position(
+    ctx: Context<ModifyPosition>,
+    params: ClosePositionParams,
+) -> Result<()> {
+    let market = &ctx.accounts.market;
+    let user_account = &ctx.accounts.user_account;
+    let clock = Clock::get()?;
+
+    // Check user has open position on this market
+    let position_index = user_account.position_index(market.market_index)?;
+    let position = &user_account.positions[position_index];
+    let position_abs_size = position.get_abs_size();
+    let (is_long, entry_price) = (position.is_long(), position.entry_price);
+
+    // Check the oracle price is fresh; closes stay open at the held price while the
+    // market is halted, until that price goes stale
+    market.check_oracle_fresh(clock.unix_timestamp)?;
+    let exit_price = ctx.accounts.market.exit_price(is_long, position_abs_size, entry_price)?;
+    check_execution_limits(
+        exit_price,
+        params.min_price,
+        params.max_price,
+        params.deadline,
+        clock.unix_timestamp,
+    )?;
+
+    let settlement = decrease_position(
+        PositionAccounts {
+            exchange_state: &mut ctx.accounts.exchange_state,
+            market: &mut ctx.accounts.market,
+            vault: &mut ctx.accounts.vault,
+            user_account: &mut ctx.accounts.user_account,
+        },
+        position_index,
+        position_abs_size,
+        exit_price,
+        clock.unix_timestamp,
+    )?;
+
+    msg!(
+        "Position closed - User: {}, P&L: {}, Final margin: {}, Source: {:?}",
+        ctx.accounts.user.key(),
+        settlement.pnl,
+        settlement.payout,
+        ctx.accounts.market.oracle_price_source
+    );
+
+    Ok(())
+}
+
+pub fn handle_add_margin(ctx: Context<ModifyPosition>, amount: u64) -> Result<()> {
+    let market = &ctx.accounts.market;
+    let user_account = &mut ctx.accounts.user_account;
+    let vault = &mut ctx.accounts.vault;
 
-    user_account.total_fees_paid = user_account.total_fees_paid
-        .checked_add(close_fee)
+    require!(amount > 0, PerpExchangeError::InvalidAmount);
+
+    // Check user has open position on this market
+    let position_index = user_account.position_index(market.market_index)?;
+
+    // Settle funding before touching the margin
+    settle_funding(market, vault, user_account, position_index)?;
+    require!(
+        user_account.collateral_balance >= amount,
+        PerpExchangeError::InsufficientCollateral
+    );
+
+    // Move collateral into the position
+    user_account.collateral_balance -= amount;
+    let position = &mut user_account.positions[position_index];
+    position.margin = position.margin
+        .checked_add(amount)
         .ok_or(PerpExchangeError::MathOverflow)?;
+    position.refresh_leverage();
 
-    exchange_state.total_volume = exchange_state.total_volume
-        .checked_add(position_abs_size)
+    vault.reserved_collateral = vault.reserved_collateral
+        .checked_add(amount)
         .ok_or(PerpExchangeError::MathOverflow)?;
 
-    // Clear position
-    user_account.position = Default::default();
+    msg!(
+        "Margin added - User: {}, Market: {}, Amount: {}, Margin: {}",
+        ctx.accounts.user.key(),
+        market.market_index,
+        amount,
+        position.margin
+    );
+
+    Ok(())
+}
+
+pub fn handle_remove_margin(ctx: Context<ModifyPosition>, amount: u64) -> Result<()> {
+    let market = &ctx.accounts.market;
+    let user_account = &mut ctx.accounts.user_account;
+    let vault = &mut ctx.accounts.vault;
+    let clock = Clock::get()?;
+
+    require!(amount > 0, PerpExchangeError::InvalidAmount);
+
+    // Check user has open position on this market
+    let position_index = user_account.position_index(market.market_index)?;
+
+    // Check the market is not halted and the oracle price is fresh
+    market.check_not_halted()?;
+    market.check_oracle_fresh(clock.unix_timestamp)?;
+
+    // Settle funding before touching the margin
+    settle_funding(market, vault, user_account, position_index)?;
+
+    let position = &mut user_account.positions[position_index];
+    let remaining_margin = position.margin
+        .checked_sub(amount)
+        .ok_or(PerpExchangeError::InsufficientCollateral)?;
+    require!(
+        remaining_margin >= market.governance_params.min_margin,
+        PerpExchangeError::MarginTooLow
+    );
+
+    // Move collateral out of the position, which must stay above the initial
+    // margin requirement at the mark price
+    position.margin = remaining_margin;
+    position.refresh_leverage();
+    market.check_initial_margin(position)?;
+
+    user_account.collateral_balance = user_account.collateral_balance
+        .checked_add(amount)
+        .ok_or(PerpExchangeError::MathOverflow)?;
+    vault.reserved_collateral = vault.reserved_collateral
+        .checked_sub(amount)
+        .ok_or(PerpExchangeError::MathOverflow)?;
 
     msg!(
-        "Position closed - User: {}, P&L: {}, Final margin: {}",
+        "Margin removed - User: {}, Market: {}, Amount: {}, Margin: {}",
         ctx.accounts.user.key(),
-        pnl,
-        final_margin
+        market.market_index,
+        amount,
+        remaining_margin
     );
 
     Ok(())
 }
 
-/// Liquidate an under-collateralized position
+/// Liquidate an under-collateralized position, closing only as much of it as is
+/// needed to restore its health (permissionless)
 #[derive(Accounts)]
 pub struct LiquidatePosition<'info> {
     #[account(
         mut,
-        seeds = [EXCHANGE_STATE_SEED],
-        bump
+        seeds = [MARKET_SEED, &market.market_index.to_le_bytes()],
+        bump = market.bump
     )]
-    pub exchange_state: Account<'info, ExchangeState>,
+    pub market: Account<'info, Market>,
 
     #[account(
         mut,
         seeds = [USER_ACCOUNT_SEED, position_owner.key().as_ref()],
-        bump
+        bump,
+        constraint = user_account.owner == position_owner.key() @ PerpExchangeError::UnauthorizedUser
     )]
     pub user_account: Account<'info, UserAccount>,
 
@@ -316,106 +489,179 @@ pub struct LiquidatePosition<'info> {
     )]
     pub vault: Account<'info, VaultAccount>,
 
+    #[account(
+        mut,
+        address = vault.token_account
+    )]
+    pub vault_token_account: Account<'info, TokenAccount>,
+
+    #[account(address = vault_token_account.mint)]
+    pub collateral_mint: Account<'info, Mint>,
+
+    #[account(
+        mut,
+        seeds = [INSURANCE_FUND_SEED],
+        bump = insurance_fund.bump
+    )]
+    pub insurance_fund: Account<'info, InsuranceFund>,
+
+    #[account(
+        mut,
+        address = insurance_fund.token_account
+    )]
+    pub insurance_fund_token_account: Account<'info, TokenAccount>,
+
     /// The user whose position is being liquidated
     /// CHECK: This is validated through the user_account PDA
     pub position_owner: AccountInfo<'info>,
 
     /// The liquidator (can be anyone)
-    #[account(mut)]
     pub liquidator: Signer<'info>,
+
+    /// Token account receiving the liquidation reward
+    #[account(
+        mut,
+        token::mint = collateral_mint
+    )]
+    pub liquidator_token_account: Account<'info, TokenAccount>,
+
+    pub token_program: Program<'info, Token>,
 }
 
-pub fn liquidate_position(ctx: Context<LiquidatePosition>) -> Result<()> {
-    let exchange_state = &mut ctx.accounts.exchange_state;
+pub fn handle_liquidate_position(ctx: Context<LiquidatePosition>) -> Result<()> {
+    let market = &mut ctx.accounts.market;
     let user_account = &mut ctx.accounts.user_account;
     let vault = &mut ctx.accounts.vault;
+    let insurance_fund = &mut ctx.accounts.insurance_fund;
     let clock = Clock::get()?;
 
-    // Check user has open position
-    require!(user_account.position.is_open, PerpExchangeError::NoPosition);
+    // Check user has open position on this market
+    let position_index = user_account.position_index(market.market_index)?;
+
+    // Check the market is not halted and the oracle price is fresh
+    market.check_not_halted()?;
+    market.check_oracle_fresh(clock.unix_timestamp)?;
+
+    // Settle funding so the health check sees the margin actually left
+    settle_funding(market, vault, user_account, position_index)?;
 
-    // Check oracle price is fresh
-    let oracle_age = clock.unix_timestamp - exchange_state.oracle_last_update;
+    let position = &user_account.positions[position_index];
+    let is_long = position.is_long();
+    let position_margin = position.margin;
+    let position_abs_size = position.get_abs_size();
+    let position_entry_price = position.entry_price;
+
+    // Check the position's equity is below the maintenance margin on its current
+    // notional at the mark price, so a single index print cannot liquidate
     require!(
-        oracle_age <= exchange_state.governance_params.oracle_validity_period as i64,
-        PerpExchangeError::StaleOracle
+        market.is_liquidatable(position)?,
+        PerpExchangeError::PositionNotLiquidatable
     );
 
-    let position = &user_account.position;
-    let is_long = position.is_long();
+    // Close only enough to restore the maintenance margin plus the buffer, or the
+    // whole position when a partial close cannot
+    let close_size = market.liquidation_size(position)?;
+    let pnl = position.calculate_pnl_for_size(close_size, market.mark_price_ema)?;
+
+    // Liquidation reward for the liquidator, in proportion to the closed size and
+    // paid out of the position margin
+    let liquidation_reward = market.liquidation_reward(position, close_size)?;
 
-    // Calculate current P&L
-    let price_diff = if is_long {
-        exchange_state.oracle_price as i128 - position.entry_price as i128
+    let (remaining_position, realized_pnl, insurance_contribution) = if close_size == position_abs_size {
+        let remaining_margin = position_margin
+            .checked_sub(liquidation_reward)
+            .ok_or(PerpExchangeError::InvalidLiquidation)?;
+
+        // The trader's loss is settled to the P&L pool, whatever equity is left goes to the insurance fund
+        let realized_loss = if pnl < 0 {
+            pnl.unsigned_abs().min(remaining_margin as u128) as u64
+        } else {
+            0
+        };
+        (Position::default(), -(realized_loss as i128), remaining_margin - realized_loss)
     } else {
-        position.entry_price as i128 - exchange_state.oracle_price as i128
+        // The P&L on the closed size is settled against the margin the rest keeps
+        let remaining_position = market
+            .partially_liquidated(position, close_size)?
+            .ok_or(PerpExchangeError::InvalidLiquidation)?;
+        (remaining_position, pnl, 0)
     };
 
-    let pnl = (position.get_abs_size() as i128)
-        .checked_mul(price_diff)
-        .ok_or(PerpExchangeError::MathOverflow)?
-        .checked_div(position.entry_price as i128)
+    // Pay the liquidator and the insurance fund out of the vault
+    let payout = liquidation_reward
+        .checked_add(insurance_contribution)
         .ok_or(PerpExchangeError::MathOverflow)?;
+    vault.total_balance = vault.total_balance
+        .checked_sub(payout)
+        .ok_or(PerpExchangeError::InsufficientVaultBalance)?;
+
+    if liquidation_reward > 0 {
+        transfer_from_vault(
+            vault,
+            &ctx.accounts.vault_token_account,
+            &ctx.accounts.liquidator_token_account,
+            &ctx.accounts.collateral_mint,
+            &ctx.accounts.token_program,
+            liquidation_reward,
+        )?;
+    }
+    if insurance_contribution > 0 {
+        transfer_from_vault(
+            vault,
+            &ctx.accounts.vault_token_account,
+            &ctx.accounts.insurance_fund_token_account,
+            &ctx.accounts.collateral_mint,
+            &ctx.accounts.token_program,
+            insurance_contribution,
+        )?;
+    }
 
-    // Calculate current margin value
-    let current_margin_value = (position.margin as i128)
-        .checked_add(pnl)
+    insurance_fund.balance = insurance_fund.balance
+        .checked_add(insurance_contribution)
         .ok_or(PerpExchangeError::MathOverflow)?;
 
-    // Check if position is liquidatable
-    let liquidation_threshold = (position.margin as u128)
-        .checked_mul(exchange_state.governance_params.liquidation_threshold as u128)
-        .ok_or(PerpExchangeError::MathOverflow)?
-        .checked_div(10000)
-        .ok_or(PerpExchangeError::MathOverflow)? as i128;
-
-    require!(
-        current_margin_value <= liquidation_threshold,
-        PerpExchangeError::PositionNotLiquidatable
-    );
-
-    // Liquidation fee for liquidator (e.g., 1% of position margin)
-    let liquidation_reward = position.margin
-        .checked_mul(100)
-        .ok_or(PerpExchangeError::MathOverflow)?
-        .checked_div(10000)
-        .ok_or(PerpExchangeError::MathOverflow)?;
+    // Settle the realized P&L against the vault's P&L pool
+    let realized_amount = u64::try_from(realized_pnl.unsigned_abs())
+        .map_err(|_| PerpExchangeError::MathOverflow)?;
+    vault.pnl_pool = if realized_pnl > 0 {
+        vault.pnl_pool
+            .checked_sub(realized_amount)
+            .ok_or(PerpExchangeError::InsufficientVaultBalance)?
+    } else {
+        vault.pnl_pool
+            .checked_add(realized_amount)
+            .ok_or(PerpExchangeError::MathOverflow)?
+    };
 
-    // Update vault and exchange state
+    // Update vault reserved collateral to the margin left in the position
     vault.reserved_collateral = vault.reserved_collateral
-        .checked_sub(position.margin)
+        .checked_sub(position_margin)
+        .and_then(|reserved| reserved.checked_add(remaining_position.margin))
         .ok_or(PerpExchangeError::MathOverflow)?;
 
-    // Update global position counts
-    if is_long {
-        exchange_state.total_long_positions = exchange_state.total_long_positions
-            .checked_sub(position.get_abs_size())
-            .ok_or(PerpExchangeError::MathOverflow)?;
-    } else {
-        exchange_state.total_short_positions = exchange_state.total_short_positions
-            .checked_sub(position.get_abs_size())
-            .ok_or(PerpExchangeError::MathOverflow)?;
-    }
+    // The close is settled at the mark, but the closed base still goes back to the
+    // vAMM as it would on a regular close
+    market.unwind_vamm(is_long, close_size, position_entry_price);
 
-    // If there's remaining margin after liquidation, it goes to insurance fund
-    if current_margin_value > liquidation_reward as i128 {
-        let insurance_contribution = (current_margin_value as u64)
-            .checked_sub(liquidation_reward)
-            .ok_or(PerpExchangeError::MathOverflow)?;
-        
-        exchange_state.insurance_fund_balance = exchange_state.insurance_fund_balance
-            .checked_add(insurance_contribution)
-            .ok_or(PerpExchangeError::MathOverflow)?;
-    }
+    // Update market statistics
+    market.remove_open_interest(is_long, close_size)?;
+    market.record_volume(close_size)?;
+
+    // Reconcile the vault ledger with custody
+    ctx.accounts.vault_token_account.reload()?;
+    vault.check_reconciled(ctx.accounts.vault_token_account.amount)?;
 
-    // Clear position
-    user_account.position = Default::default();
+    // Shrink or clear the position
+    user_account.positions[position_index] = remaining_position;
 
     msg!(
-        "Position liquidated - Owner: {}, Liquidator: {}, Reward: {}",
+        "Position liquidated - Owner: {}, Liquidator: {}, Closed size: {}, Remaining size: {}, Reward: {}, Insurance: {}",
         ctx.accounts.position_owner.key(),
         ctx.accounts.liquidator.key(),
-        liquidation_reward
+        close_size,
+        position_abs_size - close_size,
+        liquidation_reward,
+        insurance_contribution
     );
 
     Ok(())
@@ -0,0 +1,346 @@
+use anchor_lang::prelude::*;
+use anchor_spl::token::{Mint, Token, TokenAccount};
+use crate::state::{
+    ExchangeState, Market, UserAccount, VaultAccount, TriggerDirection, TriggerKind, TriggerOrder,
+    TriggerOrders,
+};
+use crate::accounting::{decrease_position, PositionAccounts};
+use crate::instructions::check_execution_limits;
+use crate::constants::*;
+use crate::error::PerpExchangeError;
+use crate::price::Price;
+use crate::utils::transfer_from_vault;
+
+/// Create the account holding a user's trigger orders
+#[derive(Accounts)]
+pub struct CreateTriggerOrders<'info> {
+    #[account(
+        init,
+        payer = user,
+        space = TriggerOrders::SPACE,
+        seeds = [TRIGGER_ORDERS_SEED, user.key().as_ref()],
+        bump
+    )]
+    pub trigger_orders: Account<'info, TriggerOrders>,
+
+    #[account(mut)]
+    pub user: Signer<'info>,
+
+    pub system_program: Program<'info, System>,
+}
+
+pub fn handle_create_trigger_orders(ctx: Context<CreateTriggerOrders>) -> Result<()> {
+    let trigger_orders = &mut ctx.accounts.trigger_orders;
+
+    trigger_orders.owner = ctx.accounts.user.key();
+    trigger_orders.next_order_id = 0;
+    trigger_orders.orders = Default::default();
+
+    msg!("Trigger orders account created for: {}", ctx.accounts.user.key());
+    Ok(())
+}
+
+/// Place or cancel a trigger order
+#[derive(Accounts)]
+pub struct ManageTriggerOrder<'info> {
+    #[account(
+        mut,
+        seeds = [TRIGGER_ORDERS_SEED, user.key().as_ref()],
+        bump,
+        constraint = trigger_orders.owner == user.key() @ PerpExchangeError::UnauthorizedUser
+    )]
+    pub trigger_orders: Account<'info, TriggerOrders>,
+
+    #[account(
+        seeds = [USER_ACCOUNT_SEED, user.key().as_ref()],
+        bump,
+        constraint = user_account.owner == user.key() @ PerpExchangeError::UnauthorizedUser
+    )]
+    pub user_account: Account<'info, UserAccount>,
+
+    pub user: Signer<'info>,
+}
+
+/// Parameters for placing a trigger order
+#[derive(AnchorSerialize, AnchorDeserialize)]
+pub struct PlaceTriggerOrderParams {
+    pub market_index: u16,
+    pub kind: TriggerKind,
+    pub direction: TriggerDirection,
+    /// Trigger price
+    pub trigger_price: Price,
+    /// Trailing distance, only used by trailing stops
+    pub trail_distance: Price,
+    /// Size to close when triggered, 0 closes the whole position
+    pub size: u64,
+    /// Worst fill price accepted when executed: the lowest for a long's exit, the
+    /// highest for a short's
+    pub limit_price: Option<Price>,
+}
+
+pub fn handle_place_trigger_order(
+    ctx: Context<ManageTriggerOrder>,
+    params: PlaceTriggerOrderParams,
+) -> Result<()> {
+    let trigger_orders = &mut ctx.accounts.trigger_orders;
+
+    require!(!params.trigger_price.is_zero(), PerpExchangeError::InvalidPrice);
+    require!(params.limit_price != Some(Price::ZERO), PerpExchangeError::InvalidPrice);
+    require!(
+        (params.kind == TriggerKind::TrailingStop) == (params.trail_distance > Price::ZERO),
+        PerpExchangeError::InvalidParameter
+    );
+
+    // The order must exit an open position, and only that one
+    let user_account = &ctx.accounts.user_account;
+    let position = &user_account.positions[user_account.position_index(params.market_index)?];
+    let position_sequence = position.sequence;
+
+    let slot = trigger_orders.orders
+        .iter()
+        .position(|order| !order.is_active)
+        .ok_or(PerpExchangeError::TooManyTriggerOrders)?;
+
+    let order_id = trigger_orders.next_order_id;
+    trigger_orders.orders[slot] = TriggerOrder {
+        order_id,
+        market_index: params.market_index,
+        position_sequence,
+        kind: params.kind,
+        direction: params.direction,
+        trigger_price: params.trigger_price,
+        trail_distance: params.trail_distance,
+        limit_price: params.limit_price,
+        size: params.size,
+        is_active: true,
+    };
+    trigger_orders.next_order_id = order_id
+        .checked_add(1)
+        .ok_or(PerpExchangeError::MathOverflow)?;
+
+    msg!(
+        "Trigger order {} placed - User: {}, Market: {}, Trigger price: {}",
+        order_id,
+        trigger_orders.owner,
+        params.market_index,
+        params.trigger_price
+    );
+    Ok(())
+}
+
+pub fn handle_cancel_trigger_order(ctx: Context<ManageTriggerOrder>, order_id: u64) -> Result<()> {
+    let trigger_orders = &mut ctx.accounts.trigger_orders;
+
+    let index = trigger_orders.order_index(order_id)?;
+    trigger_orders.orders[index] = TriggerOrder::default();
+
+    msg!("Trigger order {} cancelled - User: {}", order_id, trigger_orders.owner);
+    Ok(())
+}
+
+/// Ratchet a trailing stop towards the current price (permissionless)
+#[derive(Accounts)]
+pub struct RefreshTriggerOrder<'info> {
+    #[account(
+        seeds = [MARKET_SEED, &market.market_index.to_le_bytes()],
+        bump = market.bump
+    )]
+    pub market: Account<'info, Market>,
+
+    #[account(
+        mut,
+        seeds = [TRIGGER_ORDERS_SEED, trigger_orders.owner.as_ref()],
+        bump
+    )]
+    pub trigger_orders: Account<'info, TriggerOrders>,
+}
+
+pub fn handle_refresh_trigger_order(ctx: Context<RefreshTriggerOrder>, order_id: u64) -> Result<()> {
+    let market = &ctx.accounts.market;
+    let trigger_orders = &mut ctx.accounts.trigger_orders;
+    let clock = Clock::get()?;
+
+    market.check_oracle_fresh(clock.unix_timestamp)?;
+
+    let index = trigger_orders.order_index(order_id)?;
+    let order = &mut trigger_orders.orders[index];
+    require!(order.market_index == market.market_index, PerpExchangeError::OrderNotFound);
+    order.ratchet(market.oracle_price);
+
+    msg!("Trigger order {} refreshed - Trigger price: {}", order_id, order.trigger_price);
+    Ok(())
+}
+
+/// Execute a triggered order (permissionless, paid to the keeper)
+#[derive(Accounts)]
+pub struct ExecuteTriggerOrder<'info> {
+    #[account(
+        mut,
+        seeds = [EXCHANGE_STATE_SEED],
+        bump
+    )]
+    pub exchange_state: Account<'info, ExchangeState>,
+
+    #[account(
+        mut,
+        seeds = [MARKET_SEED, &market.market_index.to_le_bytes()],
+        bump = market.bump
+    )]
+    pub market: Account<'info, Market>,
+
+    #[account(
+        mut,
+        seeds = [USER_ACCOUNT_SEED, position_owner.key().as_ref()],
+        bump,
+        constraint = user_account.owner == position_owner.key() @ PerpExchangeError::UnauthorizedUser
+    )]
+    pub user_account: Account<'info, UserAccount>,
+
+    #[account(
+        mut,
+        seeds = [TRIGGER_ORDERS_SEED, position_owner.key().as_ref()],
+        bump,
+        constraint = trigger_orders.owner == position_owner.key() @ PerpExchangeError::UnauthorizedUser
+    )]
+    pub trigger_orders: Account<'info, TriggerOrders>,
+
+    #[account(
+        mut,
+        seeds = [VAULT_SEED],
+        bump = vault.bump
+    )]
+    pub vault: Account<'info, VaultAccount>,
+
+    #[account(
+        mut,
+        address = vault.token_account
+    )]
+    pub vault_token_account: Account<'info, TokenAccount>,
+
+    #[account(address = vault_token_account.mint)]
+    pub collateral_mint: Account<'info, Mint>,
+
+    /// The user whose position is being exited
+    /// CHECK: This is validated through the user_account PDA
+    pub position_owner: AccountInfo<'info>,
+
+    /// The keeper (can be anyone)
+    pub keeper: Signer<'info>,
+
+    /// Token account receiving the keeper fee
+    #[account(
+        mut,
+        token::mint = collateral_mint
+    )]
+    pub keeper_token_account: Account<'info, TokenAccount>,
+
+    pub token_program: Program<'info, Token>,
+}
+
+pub fn handle_execute_trigger_order(ctx: Context<ExecuteTriggerOrder>, order_id: u64) -> Result<()> {
+    let market = &ctx.accounts.market;
+    let trigger_orders = &mut ctx.accounts.trigger_orders;
+    let clock = Clock::get()?;
+
+    // Check the market is not halted and the oracle price is fresh. Unlike direct
+    // closes, triggers do not execute at the held price while the market is halted:
+    // owners can still close themselves, but keepers wait for the halt to be resolved.
+    market.check_not_halted()?;
+    market.check_oracle_fresh(clock.unix_timestamp)?;
+
+    let order_index = trigger_orders.order_index(order_id)?;
+    let mut order = trigger_orders.orders[order_index].clone();
+    require!(order.market_index == market.market_index, PerpExchangeError::OrderNotFound);
+
+    // Triggers watch the oracle; the exit itself fills at the market's execution price
+    order.ratchet(market.oracle_price);
+    require!(order.is_triggered(market.oracle_price), PerpExchangeError::TriggerConditionNotMet);
+
+    // Close the requested size, or the whole position
+    let position_index = ctx.accounts.user_account.position_index(market.market_index)?;
+    let position = &ctx.accounts.user_account.positions[position_index];
+    require!(order.exits(position), PerpExchangeError::StaleTriggerOrder);
+    let position_abs_size = position.get_abs_size();
+    let is_long = position.is_long();
+    let close_size = if order.size == 0 {
+        position_abs_size
+    } else {
+        order.size.min(position_abs_size)
+    };
+    let exit_price = ctx.accounts.market.exit_price(is_long, close_size, position.entry_price)?;
+
+    // The fill includes price impact and the skew premium, which the keeper can
+    // influence, so hold it to the owner's limit
+    let (min_price, max_price) = if is_long {
+        (order.limit_price, None)
+    } else {
+        (None, order.limit_price)
+    };
+    check_execution_limits(exit_price, min_price, max_price, None, clock.unix_timestamp)?;
+
+    let settlement = decrease_position(
+        PositionAccounts {
+            exchange_state: &mut ctx.accounts.exchange_state,
+            market: &mut ctx.accounts.market,
+            vault: &mut ctx.accounts.vault,
+            user_account: &mut ctx.accounts.user_account,
+        },
+        position_index,
+        close_size,
+        exit_price,
+        clock.unix_timestamp,
+    )?;
+
+    // Orders left on a closed position can never execute
+    let trigger_orders = &mut ctx.accounts.trigger_orders;
+    trigger_orders.orders[order_index] = TriggerOrder::default();
+    if close_size == position_abs_size {
+        for other in trigger_orders.orders.iter_mut() {
+            if other.market_index == order.market_index {
+                *other = TriggerOrder::default();
+            }
+        }
+    }
+
+    // Pay the keeper out of the user's proceeds
+    let keeper_fee = ((settlement.released_margin as u128)
+        .checked_mul(ctx.accounts.market.governance_params.keeper_fee_rate as u128)
+        .ok_or(PerpExchangeError::MathOverflow)?
+        .checked_div(10000)
+        .ok_or(PerpExchangeError::MathOverflow)? as u64)
+        .min(settlement.payout);
+
+    if keeper_fee > 0 {
+        let user_account = &mut ctx.accounts.user_account;
+        user_account.collateral_balance -= keeper_fee;
+
+        let vault = &mut ctx.accounts.vault;
+        vault.total_balance = vault.total_balance
+            .checked_sub(keeper_fee)
+            .ok_or(PerpExchangeError::InsufficientVaultBalance)?;
+
+        transfer_from_vault(
+            &ctx.accounts.vault,
+            &ctx.accounts.vault_token_account,
+            &ctx.accounts.keeper_token_account,
+            &ctx.accounts.collateral_mint,
+            &ctx.accounts.token_program,
+            keeper_fee,
+        )?;
+
+        // Reconcile the vault ledger with custody
+        ctx.accounts.vault_token_account.reload()?;
+        ctx.accounts.vault.check_reconciled(ctx.accounts.vault_token_account.amount)?;
+    }
+
+    msg!(
+        "Trigger order {} executed - Owner: {}, Keeper: {}, Closed size: {}, P&L: {}, Keeper fee: {}",
+        order_id,
+        ctx.accounts.position_owner.key(),
+        ctx.accounts.keeper.key(),
+        close_size,
+        settlement.pnl,
+        keeper_fee
+    );
+    Ok(())
+}
@@ -0,0 +1,188 @@
+use anchor_lang::prelude::*;
+use anchor_spl::token::{Mint, Token, TokenAccount};
+use crate::state::{UserAccount, VaultAccount};
+use crate::constants::*;
+use crate::error::PerpExchangeError;
+use crate::utils::{transfer_from_vault, transfer_to_vault};
+
+/// Create a user account PDA
+#[derive(Accounts)]
+pub struct CreateUserAccount<'info> {
+    #[account(
+        init,
+        payer = user,
+        space = UserAccount::SPACE,
+        seeds = [USER_ACCOUNT_SEED, user.key().as_ref()],
+        bump
+    )]
+    pub user_account: Account<'info, UserAccount>,
+
+    #[account(mut)]
+    pub user: Signer<'info>,
+
+    pub system_program: Program<'info, System>,
+}
+
+pub fn handle_create_user_account(ctx: Context<CreateUserAccount>) -> Result<()> {
+    let user_account = &mut ctx.accounts.user_account;
+    let clock = Clock::get()?;
+
+    user_account.owner = ctx.accounts.user.key();
+    user_account.collateral_balance = 0;
+    user_account.positions = Default::default();
+    user_account.positions_opened = 0;
+    user_account.funding_payment = 0;
+    user_account.total_fees_paid = 0;
+    user_account.created_at = clock.unix_timestamp;
+
+    msg!("User account created for: {}", ctx.accounts.user.key());
+    Ok(())
+}
+
+/// Deposit collateral tokens into the vault
+#[derive(Accounts)]
+pub struct DepositCollateral<'info> {
+    #[account(
+        mut,
+        seeds = [USER_ACCOUNT_SEED, user.key().as_ref()],
+        bump,
+        constraint = user_account.owner == user.key() @ PerpExchangeError::UnauthorizedUser
+    )]
+    pub user_account: Account<'info, UserAccount>,
+
+    #[account(
+        mut,
+        seeds = [VAULT_SEED],
+        bump = vault.bump
+    )]
+    pub vault: Account<'info, VaultAccount>,
+
+    #[account(
+        mut,
+        address = vault.token_account
+    )]
+    pub vault_token_account: Account<'info, TokenAccount>,
+
+    #[account(address = vault_token_account.mint)]
+    pub collateral_mint: Account<'info, Mint>,
+
+    #[account(
+        mut,
+        token::mint = collateral_mint,
+        token::authority = user
+    )]
+    pub user_token_account: Account<'info, TokenAccount>,
+
+    #[account(mut)]
+    pub user: Signer<'info>,
+
+    pub token_program: Program<'info, Token>,
+}
+
+pub fn handle_deposit_collateral(ctx: Context<DepositCollateral>, amount: u64) -> Result<()> {
+    require!(amount > 0, PerpExchangeError::InvalidAmount);
+
+    // Transfer tokens from user to vault
+    transfer_to_vault(
+        &ctx.accounts.user_token_account,
+        &ctx.accounts.vault_token_account,
+        &ctx.accounts.collateral_mint,
+        &ctx.accounts.user,
+        &ctx.accounts.token_program,
+        amount,
+    )?;
+
+    let user_account = &mut ctx.accounts.user_account;
+    let vault = &mut ctx.accounts.vault;
+
+    // Update balances
+    user_account.collateral_balance = user_account.collateral_balance
+        .checked_add(amount)
+        .ok_or(PerpExchangeError::MathOverflow)?;
+    vault.total_balance = vault.total_balance
+        .checked_add(amount)
+        .ok_or(PerpExchangeError::MathOverflow)?;
+
+    // Reconcile the vault ledger with custody
+    ctx.accounts.vault_token_account.reload()?;
+    ctx.accounts.vault.check_reconciled(ctx.accounts.vault_token_account.amount)?;
+
+    msg!("Deposited {} collateral units for user: {}", amount, ctx.accounts.user.key());
+    Ok(())
+}
+
+/// Withdraw free collateral tokens from the vault. Margin is isolated per position:
+/// free collateral is not part of any position's equity, so withdrawing it cannot
+/// change a position's health and needs no margin check. Funding a position owes
+/// falls back to its margin once free collateral runs out.
+#[derive(Accounts)]
+pub struct WithdrawCollateral<'info> {
+    #[account(
+        mut,
+        seeds = [USER_ACCOUNT_SEED, user.key().as_ref()],
+        bump,
+        constraint = user_account.owner == user.key() @ PerpExchangeError::UnauthorizedUser
+    )]
+    pub user_account: Account<'info, UserAccount>,
+
+    #[account(
+        mut,
+        seeds = [VAULT_SEED],
+        bump = vault.bump
+    )]
+    pub vault: Account<'info, VaultAccount>,
+
+    #[account(
+        mut,
+        address = vault.token_account
+    )]
+    pub vault_token_account: Account<'info, TokenAccount>,
+
+    #[account(address = vault_token_account.mint)]
+    pub collateral_mint: Account<'info, Mint>,
+
+    #[account(
+        mut,
+        token::mint = collateral_mint
+    )]
+    pub user_token_account: Account<'info, TokenAccount>,
+
+    #[account(mut)]
+    pub user: Signer<'info>,
+
+    pub token_program: Program<'info, Token>,
+}
+
+pub fn handle_withdraw_collateral(ctx: Context<WithdrawCollateral>, amount: u64) -> Result<()> {
+    let user_account = &mut ctx.accounts.user_account;
+    let vault = &mut ctx.accounts.vault;
+
+    require!(amount > 0, PerpExchangeError::InvalidAmount);
+    require!(
+        user_account.collateral_balance >= amount,
+        PerpExchangeError::InsufficientCollateral
+    );
+
+    // Update balances
+    user_account.collateral_balance -= amount;
+    vault.total_balance = vault.total_balance
+        .checked_sub(amount)
+        .ok_or(PerpExchangeError::InsufficientVaultBalance)?;
+
+    // Transfer tokens from vault to user
+    transfer_from_vault(
+        &ctx.accounts.vault,
+        &ctx.accounts.vault_token_account,
+        &ctx.accounts.user_token_account,
+        &ctx.accounts.collateral_mint,
+        &ctx.accounts.token_program,
+        amount,
+    )?;
+
+    // Reconcile the vault ledger with custody
+    ctx.accounts.vault_token_account.reload()?;
+    ctx.accounts.vault.check_reconciled(ctx.accounts.vault_token_account.amount)?;
+
+    msg!("Withdrawn {} collateral units for user: {}", amount, ctx.accounts.user.key());
+    Ok(())
+}
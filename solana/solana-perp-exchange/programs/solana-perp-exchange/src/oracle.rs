@@ -0,0 +1,277 @@
+use anchor_lang::prelude::*;
+use crate::state::{Market, OracleSource, PriceHistory, PriceUpdate, SourcePrice};
+use crate::error::PerpExchangeError;
+use crate::price::{self, Rounding};
+
+// Pyth v2 price account layout
+const PYTH_MAGIC: u32 = 0xa1b2c3d4;
+const PYTH_VERSION: u32 = 2;
+const PYTH_ACCOUNT_TYPE_PRICE: u32 = 3;
+const PYTH_STATUS_TRADING: u32 = 1;
+const PYTH_PRICE_ACCOUNT_MIN_LEN: usize = 240;
+
+const MAGIC_OFFSET: usize = 0;
+const VERSION_OFFSET: usize = 4;
+const ACCOUNT_TYPE_OFFSET: usize = 8;
+const EXPONENT_OFFSET: usize = 20;
+const PUBLISH_TIME_OFFSET: usize = 96;
+const AGG_PRICE_OFFSET: usize = 208;
+const AGG_CONF_OFFSET: usize = 216;
+const AGG_STATUS_OFFSET: usize = 224;
+
+/// Aggregate price read from a Pyth price account
+pub struct PythPrice {
+    /// Price in units of 10^exponent
+    pub price: u64,
+    /// Confidence interval in the same units as `price`
+    pub confidence: u64,
+    pub exponent: i32,
+    /// Timestamp the aggregate price was published at
+    pub publish_time: i64,
+}
+
+/// Read the aggregate price from a Pyth-format price account, checking it is
+/// owned by `oracle_program`, is a price account and is currently trading
+pub fn load_pyth_price(price_feed: &AccountInfo, oracle_program: &Pubkey) -> Result<PythPrice> {
+    require_keys_eq!(*price_feed.owner, *oracle_program, PerpExchangeError::InvalidOracle);
+
+    let data = price_feed.try_borrow_data()?;
+    require!(data.len() >= PYTH_PRICE_ACCOUNT_MIN_LEN, PerpExchangeError::InvalidOracle);
+    require!(
+        read_u32(&data, MAGIC_OFFSET) == PYTH_MAGIC
+            && read_u32(&data, VERSION_OFFSET) == PYTH_VERSION
+            && read_u32(&data, ACCOUNT_TYPE_OFFSET) == PYTH_ACCOUNT_TYPE_PRICE,
+        PerpExchangeError::InvalidOracle
+    );
+    require!(
+        read_u32(&data, AGG_STATUS_OFFSET) == PYTH_STATUS_TRADING,
+        PerpExchangeError::StaleOracle
+    );
+
+    let price = read_i64(&data, AGG_PRICE_OFFSET);
+    require!(price > 0, PerpExchangeError::InvalidPrice);
+
+    Ok(PythPrice {
+        price: price as u64,
+        confidence: read_u64(&data, AGG_CONF_OFFSET),
+        exponent: read_u32(&data, EXPONENT_OFFSET) as i32,
+        publish_time: read_i64(&data, PUBLISH_TIME_OFFSET),
+    })
+}
+
+/// Validate a Pyth price against the market's feed configuration and record it as
+/// the market's Pyth source price. A price outside its confidence bounds is
+/// rejected, so the market falls back to its next source once this one goes stale.
+pub fn apply_pyth_price(market: &mut Market, price: &PythPrice, now: i64) -> Result<PriceUpdate> {
+    // The feed must report in the market's price exponent
+    require!(price.exponent == market.price_exponent, PerpExchangeError::InvalidOracle);
+
+    // Publish time must be recent; future and backwards times are rejected when recorded
+    require!(
+        now - price.publish_time <= market.governance_params.oracle_validity_period as i64,
+        PerpExchangeError::StaleOracle
+    );
+
+    // A wider confidence interval is the conservative side
+    let confidence = price::from_raw(price.confidence, market.price_exponent, Rounding::Up)?.get();
+    let normalized = market.normalize_price(price.price)?;
+    market.check_oracle_confidence(normalized, confidence)?;
+
+    let reporter = market.oracle_feed;
+    market.record_source_price(
+        OracleSource::Pyth,
+        SourcePrice {
+            price: normalized,
+            confidence,
+            timestamp: price.publish_time,
+            reporter,
+        },
+        now,
+    )
+}
+
+/// Record the outcome of a price update in the market's price history and the logs
+pub fn log_price_update(market: &Market, price_history: &mut PriceHistory, update: PriceUpdate) {
+    match update {
+        PriceUpdate::Applied => {
+            price_history.record(market.oracle_price, market.oracle_last_update);
+            msg!(
+                "Oracle price for market {} updated to: {} (source {:?})",
+                market.market_index,
+                market.oracle_price,
+                market.oracle_price_source
+            );
+        }
+        PriceUpdate::Halted => msg!(
+            "Market {} halted - price {} from {:?} exceeds the circuit breaker limits",
+            market.market_index,
+            market.pending_price,
+            market.pending_price_source
+        ),
+        PriceUpdate::Unchanged => msg!(
+            "Price recorded for market {}, index still priced by {:?}",
+            market.market_index,
+            market.oracle_price_source
+        ),
+    }
+}
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+fn read_u64(data: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(data[offset..offset + 8].try_into().unwrap())
+}
+
+fn read_i64(data: &[u8], offset: usize) -> i64 {
+    i64::from_le_bytes(data[offset..offset + 8].try_into().unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::price::Price;
+    use crate::state::GovernanceParams;
+
+    const NOW: i64 = 1_700_000_000;
+    const EXPONENT: i32 = -8;
+
+    struct PythAccount {
+        key: Pubkey,
+        owner: Pubkey,
+        lamports: u64,
+        data: Vec<u8>,
+    }
+
+    impl PythAccount {
+        fn new(price: i64, confidence: u64, publish_time: i64) -> Self {
+            let mut data = vec![0u8; PYTH_PRICE_ACCOUNT_MIN_LEN];
+            data[MAGIC_OFFSET..MAGIC_OFFSET + 4].copy_from_slice(&PYTH_MAGIC.to_le_bytes());
+            data[VERSION_OFFSET..VERSION_OFFSET + 4].copy_from_slice(&PYTH_VERSION.to_le_bytes());
+            data[ACCOUNT_TYPE_OFFSET..ACCOUNT_TYPE_OFFSET + 4]
+                .copy_from_slice(&PYTH_ACCOUNT_TYPE_PRICE.to_le_bytes());
+            data[EXPONENT_OFFSET..EXPONENT_OFFSET + 4].copy_from_slice(&EXPONENT.to_le_bytes());
+            data[PUBLISH_TIME_OFFSET..PUBLISH_TIME_OFFSET + 8].copy_from_slice(&publish_time.to_le_bytes());
+            data[AGG_PRICE_OFFSET..AGG_PRICE_OFFSET + 8].copy_from_slice(&price.to_le_bytes());
+            data[AGG_CONF_OFFSET..AGG_CONF_OFFSET + 8].copy_from_slice(&confidence.to_le_bytes());
+            data[AGG_STATUS_OFFSET..AGG_STATUS_OFFSET + 4]
+                .copy_from_slice(&PYTH_STATUS_TRADING.to_le_bytes());
+            Self { key: Pubkey::new_unique(), owner: Pubkey::new_unique(), lamports: 0, data }
+        }
+
+        fn set_u32(&mut self, offset: usize, value: u32) {
+            self.data[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+        }
+
+        fn load(&mut self, oracle_program: &Pubkey) -> Result<PythPrice> {
+            let info = AccountInfo::new(
+                &self.key,
+                false,
+                false,
+                &mut self.lamports,
+                &mut self.data,
+                &self.owner,
+                false,
+                0,
+            );
+            load_pyth_price(&info, oracle_program)
+        }
+    }
+
+    fn pyth_market() -> Market {
+        Market {
+            price_exponent: EXPONENT,
+            oracle_sources: [OracleSource::Pyth, OracleSource::Admin, OracleSource::Admin],
+            oracle_source_count: 1,
+            governance_params: GovernanceParams {
+                oracle_validity_period: 60,
+                max_confidence_rate: 200,
+                ..Default::default()
+            },
+            ..Default::default()
+        }
+    }
+
+    fn assert_error<T>(result: Result<T>, expected: PerpExchangeError) {
+        match result {
+            Ok(_) => panic!("expected {expected:?}"),
+            Err(error) => assert_eq!(error, Error::from(expected)),
+        }
+    }
+
+    #[test]
+    fn loads_and_applies_a_trading_price() {
+        let mut account = PythAccount::new(150 * 100_000_000, 10_000_000, NOW - 5);
+        let owner = account.owner;
+        let price = account.load(&owner).unwrap();
+        assert_eq!(price.exponent, EXPONENT);
+
+        let mut market = pyth_market();
+        assert_eq!(apply_pyth_price(&mut market, &price, NOW).unwrap(), PriceUpdate::Applied);
+        assert_eq!(market.oracle_price, Price::new(150 * 1_000_000_000));
+        assert_eq!(market.oracle_confidence, 100_000_000);
+        assert_eq!(market.oracle_last_update, NOW - 5);
+    }
+
+    #[test]
+    fn rejects_account_with_wrong_owner() {
+        let mut account = PythAccount::new(100, 1, NOW);
+        assert_error(account.load(&Pubkey::new_unique()), PerpExchangeError::InvalidOracle);
+    }
+
+    #[test]
+    fn rejects_wrong_magic_version_or_account_type() {
+        for (offset, value) in [
+            (MAGIC_OFFSET, 0xdeadbeef),
+            (VERSION_OFFSET, PYTH_VERSION + 1),
+            (ACCOUNT_TYPE_OFFSET, PYTH_ACCOUNT_TYPE_PRICE + 1),
+        ] {
+            let mut account = PythAccount::new(100, 1, NOW);
+            account.set_u32(offset, value);
+            let owner = account.owner;
+            assert_error(account.load(&owner), PerpExchangeError::InvalidOracle);
+        }
+    }
+
+    #[test]
+    fn rejects_price_that_is_not_trading() {
+        let mut account = PythAccount::new(100, 1, NOW);
+        account.set_u32(AGG_STATUS_OFFSET, 0);
+        let owner = account.owner;
+        assert_error(account.load(&owner), PerpExchangeError::StaleOracle);
+    }
+
+    #[test]
+    fn rejects_exponent_mismatch() {
+        let mut account = PythAccount::new(100, 1, NOW);
+        account.set_u32(EXPONENT_OFFSET, (EXPONENT - 1) as u32);
+        let owner = account.owner;
+        let price = account.load(&owner).unwrap();
+        assert_error(apply_pyth_price(&mut pyth_market(), &price, NOW), PerpExchangeError::InvalidOracle);
+    }
+
+    #[test]
+    fn rejects_stale_and_future_publish_times() {
+        let mut market = pyth_market();
+        let stale = PythPrice { price: 100, confidence: 0, exponent: EXPONENT, publish_time: NOW - 61 };
+        assert_error(apply_pyth_price(&mut market, &stale, NOW), PerpExchangeError::StaleOracle);
+
+        let future = PythPrice { publish_time: NOW + 1, ..stale };
+        assert_error(apply_pyth_price(&mut market, &future, NOW), PerpExchangeError::InvalidOracle);
+    }
+
+    #[test]
+    fn rejects_confidence_above_max_rate() {
+        let mut market = pyth_market();
+        // 2% is the limit
+        let at_limit = PythPrice { price: 10_000, confidence: 200, exponent: EXPONENT, publish_time: NOW };
+        assert!(apply_pyth_price(&mut market, &at_limit, NOW).is_ok());
+
+        let too_wide = PythPrice { confidence: 201, ..at_limit };
+        assert_error(
+            apply_pyth_price(&mut market, &too_wide, NOW),
+            PerpExchangeError::OracleConfidenceTooWide,
+        );
+    }
+}
@@ -0,0 +1,189 @@
+use anchor_lang::prelude::*;
+use crate::constants::{MAX_PRICE_EXPONENT, MIN_PRICE_EXPONENT, PRICE_DECIMALS};
+use crate::error::PerpExchangeError;
+
+/// Fixed-point decimal price with `PRICE_DECIMALS` decimal places, i.e. the value
+/// of one whole unit of the base asset in whole collateral tokens, multiplied by
+/// `PRICE_PRECISION`. A price of 0.00042 is stored as 420_000.
+///
+/// Index, mark, entry, order, trigger and slippage prices all use this type.
+/// Oracle sources report raw prices in units of 10^`price_exponent` of their
+/// market as plain integers, which are converted with `from_raw` when recorded,
+/// so a raw price cannot be passed where a fixed-point one is expected.
+#[derive(
+    AnchorSerialize, AnchorDeserialize, Clone, Copy, Default, Debug, PartialEq, Eq, PartialOrd, Ord,
+    bytemuck::Pod, bytemuck::Zeroable,
+)]
+#[repr(transparent)]
+pub struct Price(u64);
+
+impl Price {
+    pub const ZERO: Price = Price(0);
+
+    /// Price from a value already in fixed-point units
+    pub const fn new(value: u64) -> Self {
+        Price(value)
+    }
+
+    /// Value in fixed-point units
+    pub const fn get(self) -> u64 {
+        self.0
+    }
+
+    pub const fn is_zero(self) -> bool {
+        self.0 == 0
+    }
+
+    pub fn saturating_add(self, other: Price) -> Price {
+        Price(self.0.saturating_add(other.0))
+    }
+
+    pub fn saturating_sub(self, other: Price) -> Price {
+        Price(self.0.saturating_sub(other.0))
+    }
+
+    pub fn abs_diff(self, other: Price) -> u64 {
+        self.0.abs_diff(other.0)
+    }
+}
+
+impl From<Price> for u128 {
+    fn from(price: Price) -> u128 {
+        price.0 as u128
+    }
+}
+
+impl From<Price> for i128 {
+    fn from(price: Price) -> i128 {
+        price.0 as i128
+    }
+}
+
+impl TryFrom<u128> for Price {
+    type Error = Error;
+
+    fn try_from(value: u128) -> Result<Price> {
+        u64::try_from(value)
+            .map(Price)
+            .map_err(|_| error!(PerpExchangeError::MathOverflow))
+    }
+}
+
+impl TryFrom<i128> for Price {
+    type Error = Error;
+
+    fn try_from(value: i128) -> Result<Price> {
+        u64::try_from(value)
+            .map(Price)
+            .map_err(|_| error!(PerpExchangeError::MathOverflow))
+    }
+}
+
+impl std::fmt::Display for Price {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+/// Direction a fixed-point result is rounded in. Callers pick the direction that
+/// leaves the rounding error with the protocol: amounts owed to the protocol round
+/// up, amounts paid out by it round down.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Rounding {
+    Down,
+    Up,
+}
+
+/// Check a market's raw price exponent is within the supported range
+pub fn validate_exponent(exponent: i32) -> Result<()> {
+    require!(
+        (MIN_PRICE_EXPONENT..=MAX_PRICE_EXPONENT).contains(&exponent),
+        PerpExchangeError::InvalidParameter
+    );
+    Ok(())
+}
+
+/// Convert a raw oracle value of `raw * 10^exponent` to a fixed-point price
+pub fn from_raw(raw: u64, exponent: i32, rounding: Rounding) -> Result<Price> {
+    validate_exponent(exponent)?;
+
+    let shift = exponent + PRICE_DECIMALS as i32;
+    let scale = 10u128.pow(shift.unsigned_abs());
+    let price = if shift >= 0 {
+        mul_div(raw as u128, scale, 1, rounding)?
+    } else {
+        mul_div(raw as u128, 1, scale, rounding)?
+    };
+    Price::try_from(price)
+}
+
+/// `a * b / c`, rounded in the given direction
+pub fn mul_div(a: u128, b: u128, c: u128, rounding: Rounding) -> Result<u128> {
+    require!(c > 0, PerpExchangeError::MathOverflow);
+    let product = a.checked_mul(b).ok_or(PerpExchangeError::MathOverflow)?;
+    Ok(match rounding {
+        Rounding::Down => product / c,
+        Rounding::Up => product.div_ceil(c),
+    })
+}
+
+/// Signed `a * b / c` for a positive `c`. `Rounding::Down` rounds towards negative
+/// infinity, so a gain is rounded down and a loss is rounded up in size.
+pub fn mul_div_signed(a: i128, b: i128, c: i128, rounding: Rounding) -> Result<i128> {
+    require!(c > 0, PerpExchangeError::MathOverflow);
+    let product = a.checked_mul(b).ok_or(PerpExchangeError::MathOverflow)?;
+    let quotient = product.div_euclid(c);
+    Ok(match rounding {
+        Rounding::Down => quotient,
+        Rounding::Up => quotient + (product.rem_euclid(c) > 0) as i128,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn from_raw_scales_to_price_precision() {
+        // Pyth-style 1e-8 units
+        assert_eq!(from_raw(6_000_012_345_678, -8, Rounding::Down).unwrap(), Price::new(60_000_123_456_780));
+        // Whole units and the price precision itself
+        assert_eq!(from_raw(42, 0, Rounding::Down).unwrap(), Price::new(42_000_000_000));
+        assert_eq!(from_raw(420_000, -9, Rounding::Down).unwrap(), Price::new(420_000));
+    }
+
+    #[test]
+    fn from_raw_rounds_finer_exponents_as_asked() {
+        assert_eq!(from_raw(1_999, -12, Rounding::Down).unwrap(), Price::new(1));
+        assert_eq!(from_raw(1_999, -12, Rounding::Up).unwrap(), Price::new(2));
+        assert_eq!(from_raw(2_000, -12, Rounding::Up).unwrap(), Price::new(2));
+        assert_eq!(from_raw(1, -18, Rounding::Down).unwrap(), Price::new(0));
+    }
+
+    #[test]
+    fn from_raw_rejects_unsupported_exponents_and_overflow() {
+        assert!(from_raw(1, MAX_PRICE_EXPONENT + 1, Rounding::Down).is_err());
+        assert!(from_raw(1, MIN_PRICE_EXPONENT - 1, Rounding::Down).is_err());
+        assert!(from_raw(u64::MAX, 0, Rounding::Down).is_err());
+    }
+
+    #[test]
+    fn mul_div_rounds_in_the_requested_direction() {
+        assert_eq!(mul_div(10, 10, 3, Rounding::Down).unwrap(), 33);
+        assert_eq!(mul_div(10, 10, 3, Rounding::Up).unwrap(), 34);
+        assert_eq!(mul_div(10, 9, 3, Rounding::Up).unwrap(), 30);
+        assert!(mul_div(1, 1, 0, Rounding::Down).is_err());
+        assert!(mul_div(u128::MAX, 2, 1, Rounding::Down).is_err());
+    }
+
+    #[test]
+    fn mul_div_signed_rounds_gains_down_and_losses_up() {
+        assert_eq!(mul_div_signed(10, 10, 3, Rounding::Down).unwrap(), 33);
+        assert_eq!(mul_div_signed(10, -10, 3, Rounding::Down).unwrap(), -34);
+        assert_eq!(mul_div_signed(10, 10, 3, Rounding::Up).unwrap(), 34);
+        assert_eq!(mul_div_signed(10, -10, 3, Rounding::Up).unwrap(), -33);
+        assert_eq!(mul_div_signed(10, -9, 3, Rounding::Down).unwrap(), -30);
+        assert!(mul_div_signed(1, 1, 0, Rounding::Down).is_err());
+        assert!(mul_div_signed(1, 1, -1, Rounding::Down).is_err());
+    }
+}
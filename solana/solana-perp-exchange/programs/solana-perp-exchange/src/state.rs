@@ -1,4 +1,11 @@
 use anchor_lang::prelude::*;
+use crate::constants::{
+    FUNDING_HISTORY_LEN, FUNDING_RATE_PRECISION, MAX_ORDERS_PER_SIDE, MAX_PUBLISHERS,
+    MAX_ORACLE_SOURCES, PRICE_HISTORY_LEN, MAX_POSITIONS, MAX_SKEW_PREMIUM_RATE,
+    MAX_SYMBOL_LEN, MAX_TRIGGER_ORDERS, VAMM_PRICE_SCALE,
+};
+use crate::error::PerpExchangeError;
+use crate::price::{self, mul_div, mul_div_signed, Price, Rounding};
 
 /// Global exchange state - equivalent to multiple Solidity contracts combined
 #[account]
@@ -8,37 +15,785 @@ pub struct ExchangeState {
     pub admin: Pubkey,
     /// Vault address
     pub vault: Pubkey,
-    /// Price oracle data
-    pub oracle_price: u64,
-    pub oracle_last_update: i64,
-    /// Funding rate data
-    pub funding_rate: i64, // signed funding rate (positive = longs pay shorts)
-    pub funding_last_update: i64,
+    /// SPL mint used as collateral
+    pub collateral_mint: Pubkey,
+    /// Decimals of the collateral mint
+    pub collateral_decimals: u8,
     /// Fee collection
     pub collected_fees: u64,
-    /// Insurance fund balance
-    pub insurance_fund_balance: u64,
+    /// Insurance fund address
+    pub insurance_fund: Pubkey,
     /// Trading pause state
     pub is_paused: bool,
+    /// Number of listed markets (next market index)
+    pub market_count: u16,
+}
+
+impl ExchangeState {
+    pub const SPACE: usize = 8 + // discriminator
+        32 + // admin
+        32 + // vault
+        32 + // collateral_mint
+        1 + // collateral_decimals
+        8 + // collected_fees
+        32 + // insurance_fund
+        1 + // is_paused
+        2; // market_count
+}
+
+/// Per-symbol market state
+#[account]
+#[derive(Default)]
+pub struct Market {
+    /// Exchange state this market belongs to
+    pub exchange_state: Pubkey,
+    /// Index the market PDA is derived from
+    pub market_index: u16,
+    /// Market symbol, zero padded (e.g. "SOL-PERP")
+    pub symbol: [u8; MAX_SYMBOL_LEN],
+    /// Index price from the oracle; fills are anchored to it
+    pub oracle_price: Price,
+    pub oracle_last_update: i64,
+    /// Mark price: exponential moving average of index prices and of fill prices clamped
+    /// to a band around the index, used for unrealized P&L and liquidations
+    pub mark_price_ema: Price,
+    pub mark_price_last_update: i64,
+    /// Oracle sources in order of preference; the first fresh one sets the index price
+    pub oracle_sources: [OracleSource; MAX_ORACLE_SOURCES],
+    pub oracle_source_count: u8,
+    /// Latest price reported by each configured source
+    pub source_prices: [SourcePrice; MAX_ORACLE_SOURCES],
+    /// Source the current index price came from
+    pub oracle_price_source: OracleSource,
+    /// Pyth price account and the program that must own it
+    pub oracle_feed: Pubkey,
+    pub oracle_program: Pubkey,
+    /// Exponent of the raw prices reported by the oracle sources (raw * 10^exponent),
+    /// fixed at listing; a Pyth feed must use the same exponent
+    pub price_exponent: i32,
+    /// Confidence interval of the last oracle price, in price units
+    pub oracle_confidence: u64,
+    /// Circuit breaker: set when a price move exceeded the limits, only closes
+    /// and margin additions are allowed until the pending price is confirmed
+    pub is_halted: bool,
+    /// Price that tripped the breaker, who reported it and from which source
+    pub pending_price: Price,
+    pub pending_price_reporter: Pubkey,
+    pub pending_price_source: OracleSource,
+    /// Start of the current price window and the oracle price at that time
+    pub price_window_start: i64,
+    pub price_window_open_price: Price,
+    /// Funding rate data
+    pub funding_rate: i64, // signed funding rate (positive = longs pay shorts)
+    pub funding_last_update: i64,
+    /// Sum of all funding rates applied so far (scaled by FUNDING_RATE_PRECISION)
+    pub cumulative_funding_index: i128,
     /// Governance parameters
     pub governance_params: GovernanceParams,
-    /// Global statistics
+    /// Market statistics
     pub total_long_positions: u64,
     pub total_short_positions: u64,
     pub total_volume: u64,
+    /// How market orders are priced
+    pub pricing_mode: PricingMode,
+    /// Virtual AMM reserves; price = quote * VAMM_PRICE_SCALE / base. Market orders
+    /// swap against them on open and close; order book fills are matched between
+    /// traders and only swap when their positions are closed.
+    pub vamm_base_reserve: u128,
+    pub vamm_quote_reserve: u128,
+    /// Market bump seed
+    pub bump: u8,
 }
 
-impl ExchangeState {
+impl Market {
     pub const SPACE: usize = 8 + // discriminator
-        32 + // admin
-        32 + // vault
+        32 + // exchange_state
+        2 + // market_index
+        MAX_SYMBOL_LEN + // symbol
         8 + 8 + // oracle
-        8 + 8 + // funding
-        8 + // collected_fees
-        8 + // insurance_fund_balance
-        1 + // is_paused
+        8 + 8 + // mark price
+        MAX_ORACLE_SOURCES + 1 + // oracle_sources
+        SourcePrice::SPACE * MAX_ORACLE_SOURCES + // source_prices
+        1 + 32 + 32 + 4 + 8 + // oracle source
+        1 + 8 + 32 + 1 + 8 + 8 + // circuit breaker
+        8 + 8 + 16 + // funding
         GovernanceParams::SPACE + // governance_params
-        8 + 8 + 8; // global stats
+        8 + 8 + 8 + // market stats
+        1 + // pricing_mode
+        16 + 16 + // vamm reserves
+        1; // bump
+
+    /// Check the oracle price was updated within the validity period
+    pub fn check_oracle_fresh(&self, now: i64) -> Result<()> {
+        let oracle_age = now - self.oracle_last_update;
+        require!(
+            oracle_age <= self.governance_params.oracle_validity_period as i64,
+            PerpExchangeError::StaleOracle
+        );
+        Ok(())
+    }
+
+    /// Check the circuit breaker has not halted the market
+    pub fn check_not_halted(&self) -> Result<()> {
+        require!(!self.is_halted, PerpExchangeError::MarketHalted);
+        Ok(())
+    }
+
+    pub fn oracle_sources(&self) -> &[OracleSource] {
+        &self.oracle_sources[..self.oracle_source_count as usize]
+    }
+
+    /// Record the latest price from `source` and re-select the index price
+    pub fn record_source_price(
+        &mut self,
+        source: OracleSource,
+        price: SourcePrice,
+        now: i64,
+    ) -> Result<PriceUpdate> {
+        let rank = self.oracle_sources()
+            .iter()
+            .position(|configured| *configured == source)
+            .ok_or(PerpExchangeError::InvalidOracle)?;
+        require!(
+            price.timestamp <= now && price.timestamp >= self.source_prices[rank].timestamp,
+            PerpExchangeError::InvalidOracle
+        );
+
+        self.source_prices[rank] = price;
+        Ok(self.select_oracle_price(now))
+    }
+
+    /// Submit the price of the first source that is still fresh through the circuit
+    /// breaker, unless it was already submitted
+    pub fn select_oracle_price(&mut self, now: i64) -> PriceUpdate {
+        let validity_period = self.governance_params.oracle_validity_period as i64;
+        let Some(rank) = (0..self.oracle_source_count as usize).find(|&rank| {
+            let price = &self.source_prices[rank];
+            !price.price.is_zero() && now - price.timestamp <= validity_period
+        }) else {
+            return PriceUpdate::Unchanged;
+        };
+
+        let source = self.oracle_sources[rank];
+        let price = self.source_prices[rank];
+        let already_submitted = if self.is_halted {
+            source == self.pending_price_source
+                && price.price == self.pending_price
+                && price.reporter == self.pending_price_reporter
+        } else {
+            source == self.oracle_price_source && price.timestamp <= self.oracle_last_update
+        };
+        if already_submitted {
+            return PriceUpdate::Unchanged;
+        }
+
+        if self.submit_oracle_price(price.price, price.timestamp, price.reporter) {
+            self.oracle_price_source = source;
+            self.oracle_confidence = price.confidence;
+            PriceUpdate::Applied
+        } else {
+            self.pending_price_source = source;
+            PriceUpdate::Halted
+        }
+    }
+
+    /// Offer a new oracle price from `reporter`. A move beyond the per-update or
+    /// per-window limit halts the market instead of being applied. While halted, a
+    /// price within the per-update limit of the pending one from a different
+    /// reporter corroborates it and resumes trading. Returns whether the price
+    /// was applied.
+    fn submit_oracle_price(&mut self, price: Price, timestamp: i64, reporter: Pubkey) -> bool {
+        let max_price_move_rate = self.governance_params.max_price_move_rate;
+
+        if self.is_halted {
+            if reporter != self.pending_price_reporter
+                && Self::within_move_rate(self.pending_price, price, max_price_move_rate)
+            {
+                self.resume_trading(price, timestamp);
+                return true;
+            }
+            self.pending_price = price;
+            self.pending_price_reporter = reporter;
+            return false;
+        }
+
+        if timestamp - self.price_window_start > self.governance_params.price_window as i64 {
+            self.price_window_start = timestamp;
+            self.price_window_open_price = self.oracle_price;
+        }
+        let within_limits = Self::within_move_rate(self.oracle_price, price, max_price_move_rate)
+            && Self::within_move_rate(
+                self.price_window_open_price,
+                price,
+                self.governance_params.max_window_move_rate,
+            );
+        if !within_limits {
+            self.is_halted = true;
+            self.pending_price = price;
+            self.pending_price_reporter = reporter;
+            return false;
+        }
+
+        self.oracle_price = price;
+        self.oracle_last_update = timestamp;
+        self.update_mark_price(price, timestamp);
+        true
+    }
+
+    /// Accept the price that tripped the circuit breaker, vouched for by the admin,
+    /// and resume trading. Returns the accepted price.
+    pub fn confirm_pending_price(&mut self, now: i64) -> Result<Price> {
+        require!(self.is_halted, PerpExchangeError::InvalidParameter);
+
+        let price = self.pending_price;
+        self.oracle_price_source = self.pending_price_source;
+        self.resume_trading(price, now);
+        Ok(price)
+    }
+
+    /// Accept `price`, lift the halt and start a new price window
+    pub fn resume_trading(&mut self, price: Price, timestamp: i64) {
+        self.oracle_price = price;
+        self.oracle_last_update = timestamp;
+        self.update_mark_price(price, timestamp);
+        self.is_halted = false;
+        self.pending_price = Price::ZERO;
+        self.pending_price_reporter = Pubkey::default();
+        self.pending_price_source = OracleSource::default();
+        self.price_window_start = timestamp;
+        self.price_window_open_price = price;
+    }
+
+    /// Move the mark price towards `price`, weighted by the time since the last
+    /// sample relative to the EMA period, so a single print cannot move it far
+    pub fn update_mark_price(&mut self, price: Price, timestamp: i64) {
+        let period = self.governance_params.mark_ema_period as i64;
+        let elapsed = timestamp.saturating_sub(self.mark_price_last_update);
+        if elapsed <= 0 {
+            return;
+        }
+
+        if period == 0 || elapsed >= period {
+            self.mark_price_ema = price;
+        } else {
+            let mark = i128::from(self.mark_price_ema);
+            let delta = (i128::from(price) - mark) * elapsed as i128 / period as i128;
+            self.mark_price_ema = Price::new((mark + delta) as u64);
+        }
+        self.mark_price_last_update = timestamp;
+    }
+
+    /// Sample a fill price into the mark price, clamped to the fill deviation band
+    /// around the index price so a single wash fill cannot drag the mark away
+    pub fn record_fill_price(&mut self, price: Price, timestamp: i64) {
+        let band = mul_div(
+            u128::from(self.oracle_price),
+            self.governance_params.max_fill_deviation_rate as u128,
+            10000,
+            Rounding::Down,
+        )
+        .map_or(Price::ZERO, |band| Price::new(band as u64));
+        let clamped = price.clamp(
+            self.oracle_price.saturating_sub(band),
+            self.oracle_price.saturating_add(band),
+        );
+        self.update_mark_price(clamped, timestamp);
+    }
+
+    /// Whether `price` is within `rate` (in basis points, 0 = unlimited) of `reference`
+    fn within_move_rate(reference: Price, price: Price, rate: u16) -> bool {
+        rate == 0
+            || (reference.abs_diff(price) as u128) * 10000 <= u128::from(reference) * (rate as u128)
+    }
+
+    /// Whether `price` is within the fill deviation band around the index price
+    pub fn within_fill_band(&self, price: Price) -> bool {
+        Self::within_move_rate(self.oracle_price, price, self.governance_params.max_fill_deviation_rate)
+    }
+
+    /// Trading fee charged on `amount`, rounded up
+    pub fn trading_fee(&self, amount: u64) -> Result<u64> {
+        let fee = mul_div(
+            amount as u128,
+            self.governance_params.trading_fee_rate as u128,
+            10000,
+            Rounding::Up,
+        )?;
+        u64::try_from(fee).map_err(|_| error!(PerpExchangeError::MathOverflow))
+    }
+
+    /// Convert a raw price reported by one of the market's oracle sources to a
+    /// fixed-point price, rejecting prices too small to represent
+    pub fn normalize_price(&self, raw: u64) -> Result<Price> {
+        let price = price::from_raw(raw, self.price_exponent, Rounding::Down)?;
+        require!(!price.is_zero(), PerpExchangeError::InvalidPrice);
+        Ok(price)
+    }
+
+    /// Check the oracle confidence interval is within the allowed fraction of the price
+    pub fn check_oracle_confidence(&self, price: Price, confidence: u64) -> Result<()> {
+        require!(
+            (confidence as u128) * 10000
+                <= u128::from(price) * (self.governance_params.max_confidence_rate as u128),
+            PerpExchangeError::OracleConfidenceTooWide
+        );
+        Ok(())
+    }
+
+    /// Margin required to open or add to `notional`: the initial margin fraction,
+    /// widened by the oracle's relative confidence interval, rounded up
+    pub fn initial_margin_requirement(&self, notional: u64) -> u64 {
+        let confidence_margin = Self::fraction_of(
+            notional,
+            self.oracle_confidence as u128,
+            u128::from(self.oracle_price).max(1),
+        );
+        Self::fraction_of(notional, self.governance_params.initial_margin_rate as u128, 10000)
+            .saturating_add(confidence_margin)
+    }
+
+    /// Equity `notional` must keep to stay open, rounded up
+    pub fn maintenance_margin_requirement(&self, notional: u64) -> u64 {
+        Self::fraction_of(notional, self.governance_params.maintenance_margin_rate as u128, 10000)
+    }
+
+    /// `notional * numerator / denominator` rounded up, saturating on overflow
+    fn fraction_of(notional: u64, numerator: u128, denominator: u128) -> u64 {
+        mul_div(notional as u128, numerator, denominator, Rounding::Up)
+            .map_or(u64::MAX, |amount| amount.min(u64::MAX as u128) as u64)
+    }
+
+    /// Equity of an open position on this market: its margin plus unrealized P&L
+    /// at the mark price, less funding accrued since it was last settled
+    pub fn position_equity(&self, position: &Position) -> Result<i128> {
+        self.position_equity_at(position, self.mark_price_ema)
+    }
+
+    fn position_equity_at(&self, position: &Position, price: Price) -> Result<i128> {
+        let pnl = position.calculate_pnl(price)?;
+        let funding_owed = position.funding_owed(self.cumulative_funding_index)?;
+        (position.margin as i128)
+            .checked_add(pnl)
+            .and_then(|equity| equity.checked_sub(funding_owed))
+            .ok_or(error!(PerpExchangeError::MathOverflow))
+    }
+
+    /// Check an open position meets the initial margin requirement on its notional
+    /// at the mark price. Its P&L is taken at the mark or at the mark after the skew
+    /// premium on closing it, whichever is worse for the position, so an entry at a
+    /// skew discount is not credited as equity while the exit would pay it back.
+    pub fn check_initial_margin(&self, position: &Position) -> Result<()> {
+        let is_long = position.is_long();
+        let exit_price = self.apply_skew_premium(self.mark_price_ema, !is_long, position.get_abs_size())?;
+        let valuation_price = if is_long {
+            exit_price.min(self.mark_price_ema)
+        } else {
+            exit_price.max(self.mark_price_ema)
+        };
+
+        let required_margin = self.initial_margin_requirement(position.notional_at(self.mark_price_ema)?);
+        require!(
+            self.position_equity_at(position, valuation_price)? >= required_margin as i128,
+            PerpExchangeError::InitialMarginNotMet
+        );
+        Ok(())
+    }
+
+    /// Whether an open position's equity has fallen below the maintenance margin
+    /// requirement on its notional at the mark price
+    pub fn is_liquidatable(&self, position: &Position) -> Result<bool> {
+        let required_margin =
+            self.maintenance_margin_requirement(position.notional_at(self.mark_price_ema)?);
+        Ok(self.position_equity(position)? < required_margin as i128)
+    }
+
+    /// Liquidator reward for closing `close_size` of `position`: the reward rate
+    /// applied to the share of the margin backing the closed size
+    pub fn liquidation_reward(&self, position: &Position, close_size: u64) -> Result<u64> {
+        let backing_margin = mul_div(
+            position.margin as u128,
+            close_size as u128,
+            position.get_abs_size() as u128,
+            Rounding::Down,
+        )?;
+        let reward = mul_div(
+            backing_margin,
+            self.governance_params.liquidation_reward_rate as u128,
+            10000,
+            Rounding::Down,
+        )?;
+        u64::try_from(reward).map_err(|_| error!(PerpExchangeError::MathOverflow))
+    }
+
+    /// `position` after a liquidation closed `close_size` of it at the mark price.
+    /// The P&L on the closed size is realized into the margin, which also pays the
+    /// liquidator's reward; `None` if the margin cannot cover both.
+    pub fn partially_liquidated(&self, position: &Position, close_size: u64) -> Result<Option<Position>> {
+        let pnl = position.calculate_pnl_for_size(close_size, self.mark_price_ema)?;
+        let reward = self.liquidation_reward(position, close_size)?;
+        let margin = (position.margin as i128)
+            .checked_add(pnl)
+            .and_then(|margin| margin.checked_sub(reward as i128))
+            .ok_or(PerpExchangeError::MathOverflow)?;
+        let Ok(margin) = u64::try_from(margin) else {
+            return Ok(None);
+        };
+
+        let remaining = i64::try_from(position.get_abs_size() - close_size)
+            .map_err(|_| PerpExchangeError::MathOverflow)?;
+        let mut liquidated = position.clone();
+        liquidated.size = if position.is_long() { remaining } else { -remaining };
+        liquidated.margin = margin;
+        liquidated.refresh_leverage();
+        Ok(Some(liquidated))
+    }
+
+    /// Size of a liquidatable `position` to close: the least that brings its equity
+    /// back to the maintenance margin plus the liquidation buffer on the remaining
+    /// notional, after the liquidator's reward. The whole position when a partial
+    /// close cannot restore it or would leave less than the minimum margin.
+    pub fn liquidation_size(&self, position: &Position) -> Result<u64> {
+        let abs_size = position.get_abs_size();
+        let equity = self.position_equity(position)?;
+        let notional = position.notional_at(self.mark_price_ema)? as i128;
+        let target_rate = (self.governance_params.maintenance_margin_rate as i128)
+            + (self.governance_params.liquidation_buffer_rate as i128);
+        let reward_rate = self.governance_params.liquidation_reward_rate as i128;
+
+        // Closing `c` out of size `A` costs rate * margin * c / A of equity and
+        // shrinks the notional to N * (A - c) / A, so the target is met from
+        // c = A * (target * N - equity) / (target * N - rate * margin)
+        let target = target_rate
+            .checked_mul(notional)
+            .ok_or(PerpExchangeError::MathOverflow)?;
+        // Rounding the P&L of the closed and the remaining size each costs up to one
+        // unit of equity, and the remaining notional rounds up by up to one unit
+        let rounding_slack = 2 * 10000 + target_rate;
+        let shortfall = target
+            .checked_sub(equity.checked_mul(10000).ok_or(PerpExchangeError::MathOverflow)?)
+            .and_then(|shortfall| shortfall.checked_add(rounding_slack))
+            .ok_or(PerpExchangeError::MathOverflow)?;
+        let relief = target - reward_rate * position.margin as i128;
+        if equity <= 0 || relief <= 0 {
+            return Ok(abs_size);
+        }
+
+        let close_size = mul_div_signed(abs_size as i128, shortfall, relief, Rounding::Up)?.max(1);
+        if close_size >= abs_size as i128 {
+            return Ok(abs_size);
+        }
+        let close_size = close_size as u64;
+
+        // Rounding aside, the rest must be healthy and keep the minimum margin
+        let Some(liquidated) = self.partially_liquidated(position, close_size)? else {
+            return Ok(abs_size);
+        };
+        if liquidated.margin < self.governance_params.min_margin || self.is_liquidatable(&liquidated)? {
+            return Ok(abs_size);
+        }
+        Ok(close_size)
+    }
+
+    pub fn add_open_interest(&mut self, is_long: bool, size: u64) -> Result<()> {
+        if is_long {
+            self.total_long_positions = self.total_long_positions
+                .checked_add(size)
+                .ok_or(PerpExchangeError::MathOverflow)?;
+        } else {
+            self.total_short_positions = self.total_short_positions
+                .checked_add(size)
+                .ok_or(PerpExchangeError::MathOverflow)?;
+        }
+        Ok(())
+    }
+
+    pub fn remove_open_interest(&mut self, is_long: bool, size: u64) -> Result<()> {
+        if is_long {
+            self.total_long_positions = self.total_long_positions
+                .checked_sub(size)
+                .ok_or(PerpExchangeError::MathOverflow)?;
+        } else {
+            self.total_short_positions = self.total_short_positions
+                .checked_sub(size)
+                .ok_or(PerpExchangeError::MathOverflow)?;
+        }
+        Ok(())
+    }
+
+    pub fn record_volume(&mut self, size: u64) -> Result<()> {
+        self.total_volume = self.total_volume
+            .checked_add(size)
+            .ok_or(PerpExchangeError::MathOverflow)?;
+        Ok(())
+    }
+
+    /// Execution price for opening `notional` on the given side, moving the
+    /// vAMM reserves when the market is in vAMM mode
+    pub fn fill_price(&mut self, is_long: bool, notional: u64) -> Result<Price> {
+        let price = match self.pricing_mode {
+            PricingMode::Oracle => self.oracle_price,
+            PricingMode::Vamm => self.swap_quote(is_long, notional as u128)?,
+        };
+        self.apply_skew_premium(price, is_long, notional)
+    }
+
+    /// Execution price for closing `size` of a position opened at `entry_price`,
+    /// moving the vAMM reserves when the market is in vAMM mode
+    pub fn exit_price(&mut self, is_long: bool, size: u64, entry_price: Price) -> Result<Price> {
+        let price = match self.pricing_mode {
+            PricingMode::Oracle => self.oracle_price,
+            PricingMode::Vamm => self.swap_position_base(is_long, size, entry_price)?,
+        };
+        self.apply_skew_premium(price, !is_long, size)
+    }
+
+    /// Return the base held by `size` of a position opened at `entry_price` to the
+    /// vAMM without pricing the exit, for closes settled at another price such as
+    /// liquidations at the mark. Does nothing outside vAMM mode. Never fails: when
+    /// the reserves cannot absorb the base they are left as they are, so a
+    /// liquidation is not blocked by the vAMM.
+    pub fn unwind_vamm(&mut self, is_long: bool, size: u64, entry_price: Price) {
+        if self.pricing_mode == PricingMode::Vamm
+            && self.swap_position_base(is_long, size, entry_price).is_err()
+        {
+            msg!("vAMM reserves cannot absorb the closed size, left unchanged");
+        }
+    }
+
+    /// Sell back (longs) or buy back (shorts) the base held by `size` of a position
+    /// opened at `entry_price`, returning the average price
+    fn swap_position_base(&mut self, is_long: bool, size: u64, entry_price: Price) -> Result<Price> {
+        // Base amount the position holds, rounded against the trader:
+        // longs sell less and shorts buy back more
+        let rounding = if is_long { Rounding::Down } else { Rounding::Up };
+        let base = mul_div(size as u128, VAMM_PRICE_SCALE, u128::from(entry_price), rounding)?;
+        self.swap_base(!is_long, base)
+    }
+
+    /// Adjust `price` by the open interest skew averaged over a trade of `size`:
+    /// buys pay a premium while longs dominate and get a discount while shorts
+    /// dominate, and the other way round for sells. The premium is capped at
+    /// `MAX_SKEW_PREMIUM_RATE` of the price, so a heavily skewed book still has a
+    /// usable price to exit at. Must be called before the trade updates open interest.
+    pub fn apply_skew_premium(&self, price: Price, is_buy: bool, size: u64) -> Result<Price> {
+        let skew_scale = self.governance_params.skew_scale as i128;
+        if skew_scale == 0 {
+            return Ok(price);
+        }
+
+        // Skew halfway through the trade, so splitting an order does not change its price
+        let half_size = (size / 2) as i128;
+        let max_skew = skew_scale * MAX_SKEW_PREMIUM_RATE as i128 / 10000;
+        let skew = (self.total_long_positions as i128 - self.total_short_positions as i128
+            + if is_buy { half_size } else { -half_size })
+            .clamp(-max_skew, max_skew);
+
+        let numerator = i128::from(price)
+            .checked_mul(skew_scale + skew)
+            .ok_or(PerpExchangeError::MathOverflow)?;
+        let adjusted = if is_buy {
+            numerator.div_euclid(skew_scale) + (numerator.rem_euclid(skew_scale) > 0) as i128
+        } else {
+            numerator.div_euclid(skew_scale)
+        };
+        require!(adjusted > 0, PerpExchangeError::InvalidPrice);
+        Price::try_from(adjusted)
+    }
+
+    /// Buy (`is_buy`) or sell base for exactly `quote`, returning the average
+    /// price rounded against the trader
+    fn swap_quote(&mut self, is_buy: bool, quote: u128) -> Result<Price> {
+        require!(quote > 0, PerpExchangeError::InvalidAmount);
+        let k = self.vamm_invariant()?;
+
+        let new_quote = if is_buy {
+            self.vamm_quote_reserve
+                .checked_add(quote)
+                .ok_or(PerpExchangeError::MathOverflow)?
+        } else {
+            self.vamm_quote_reserve
+                .checked_sub(quote)
+                .filter(|reserve| *reserve > 0)
+                .ok_or(PerpExchangeError::InsufficientLiquidity)?
+        };
+        let new_base = k.div_ceil(new_quote);
+        let base = if is_buy {
+            self.vamm_base_reserve - new_base
+        } else {
+            new_base - self.vamm_base_reserve
+        };
+        require!(base > 0, PerpExchangeError::InsufficientLiquidity);
+
+        self.vamm_quote_reserve = new_quote;
+        self.vamm_base_reserve = new_base;
+        Self::average_price(quote, base, if is_buy { Rounding::Up } else { Rounding::Down })
+    }
+
+    /// Buy (`is_buy`) or sell exactly `base`, returning the average price
+    /// rounded against the trader
+    fn swap_base(&mut self, is_buy: bool, base: u128) -> Result<Price> {
+        require!(base > 0, PerpExchangeError::InvalidAmount);
+        let k = self.vamm_invariant()?;
+
+        let new_base = if is_buy {
+            self.vamm_base_reserve
+                .checked_sub(base)
+                .filter(|reserve| *reserve > 0)
+                .ok_or(PerpExchangeError::InsufficientLiquidity)?
+        } else {
+            self.vamm_base_reserve
+                .checked_add(base)
+                .ok_or(PerpExchangeError::MathOverflow)?
+        };
+        let new_quote = k.div_ceil(new_base);
+        let quote = if is_buy {
+            new_quote - self.vamm_quote_reserve
+        } else {
+            self.vamm_quote_reserve - new_quote
+        };
+        require!(quote > 0, PerpExchangeError::InsufficientLiquidity);
+
+        self.vamm_quote_reserve = new_quote;
+        self.vamm_base_reserve = new_base;
+        Self::average_price(quote, base, if is_buy { Rounding::Up } else { Rounding::Down })
+    }
+
+    fn vamm_invariant(&self) -> Result<u128> {
+        require!(
+            self.vamm_base_reserve > 0 && self.vamm_quote_reserve > 0,
+            PerpExchangeError::InsufficientLiquidity
+        );
+        Ok(self.vamm_quote_reserve
+            .checked_mul(self.vamm_base_reserve)
+            .ok_or(PerpExchangeError::MathOverflow)?)
+    }
+
+    fn average_price(quote: u128, base: u128, rounding: Rounding) -> Result<Price> {
+        let price = mul_div(quote, VAMM_PRICE_SCALE, base, rounding)?;
+        require!(price > 0, PerpExchangeError::InvalidPrice);
+        Price::try_from(price)
+    }
+
+    /// Price the market currently trades at: the vAMM spot price or the oracle
+    /// price, adjusted for the open interest skew
+    pub fn spot_price(&self) -> Result<Price> {
+        let price = match self.pricing_mode {
+            PricingMode::Oracle => self.oracle_price,
+            PricingMode::Vamm => {
+                self.vamm_invariant()?;
+                Self::average_price(self.vamm_quote_reserve, self.vamm_base_reserve, Rounding::Down)?
+            }
+        };
+        self.apply_skew_premium(price, true, 0)
+    }
+
+    /// Premium of the spot price over the oracle price (scaled by FUNDING_RATE_PRECISION)
+    pub fn funding_premium(&self) -> Result<i64> {
+        let premium = (i128::from(self.spot_price()?) - i128::from(self.oracle_price))
+            .checked_mul(FUNDING_RATE_PRECISION)
+            .ok_or(PerpExchangeError::MathOverflow)?
+            .checked_div(i128::from(self.oracle_price))
+            .ok_or(PerpExchangeError::MathOverflow)?;
+        i64::try_from(premium).map_err(|_| error!(PerpExchangeError::MathOverflow))
+    }
+
+    /// Funding rate for one interval: the premium divided by the dampening factor
+    /// and clamped to the maximum rate (scaled by FUNDING_RATE_PRECISION,
+    /// positive = longs pay shorts)
+    pub fn funding_rate_for_premium(&self, premium: i64) -> i64 {
+        let max_rate = (self.governance_params.max_funding_rate as i128 * FUNDING_RATE_PRECISION
+            / 10000) as i64;
+        (premium / self.governance_params.funding_dampening as i64).clamp(-max_rate, max_rate)
+    }
+
+    /// Set up fresh vAMM reserves with `quote_reserve` of depth, priced at the
+    /// oracle price. Only valid while the market has no open interest.
+    pub fn reset_vamm(&mut self, quote_reserve: u128) -> Result<()> {
+        let base = mul_div(
+            quote_reserve,
+            VAMM_PRICE_SCALE,
+            u128::from(self.oracle_price),
+            Rounding::Down,
+        )?;
+        require!(base > 0, PerpExchangeError::InsufficientLiquidity);
+
+        self.vamm_quote_reserve = quote_reserve;
+        self.vamm_base_reserve = base;
+        // Reject reserves too deep to swap against
+        self.vamm_invariant()?;
+        Ok(())
+    }
+
+    /// Move the vAMM quote reserve so the vAMM price equals the oracle price. The
+    /// base reserve is kept, so the base open positions took out of the vAMM, or
+    /// put into it, can still be swapped back when they close.
+    pub fn repeg(&mut self) -> Result<()> {
+        let quote = mul_div(
+            self.vamm_base_reserve,
+            u128::from(self.oracle_price),
+            VAMM_PRICE_SCALE,
+            Rounding::Up,
+        )?;
+        require!(quote > 0, PerpExchangeError::InsufficientLiquidity);
+
+        self.vamm_quote_reserve = quote;
+        // Reject reserves too deep to swap against
+        self.vamm_invariant()?;
+        Ok(())
+    }
+}
+
+/// Source of a market's oracle price
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq, Default, Debug)]
+pub enum OracleSource {
+    /// Prices are pushed by the admin through update_price
+    #[default]
+    Admin,
+    /// Prices are read from a Pyth-format price account
+    Pyth,
+    /// Prices are the median of the market's publisher set submissions
+    Publishers,
+}
+
+/// Latest price reported by one oracle source
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Default)]
+pub struct SourcePrice {
+    pub price: Price,
+    /// Confidence interval, in price units
+    pub confidence: u64,
+    /// Time the price was published
+    pub timestamp: i64,
+    /// Key that reported the price, used for circuit breaker corroboration
+    pub reporter: Pubkey,
+}
+
+impl SourcePrice {
+    pub const SPACE: usize =
+        8 + // price
+        8 + // confidence
+        8 + // timestamp
+        32; // reporter
+}
+
+/// Outcome of offering a new oracle price to a market
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PriceUpdate {
+    /// The price became the index price
+    Applied,
+    /// The price tripped the circuit breaker and awaits confirmation
+    Halted,
+    /// The index price did not change, e.g. a preferred source is still fresh
+    Unchanged,
+}
+
+/// How a market prices market orders
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PricingMode {
+    /// Every fill executes at the oracle price
+    #[default]
+    Oracle,
+    /// Fills trade against constant-product virtual reserves and move the price
+    Vamm,
 }
 
 /// User account state - equivalent to Solidity mappings per user
@@ -47,10 +802,12 @@ impl ExchangeState {
 pub struct UserAccount {
     /// Owner of this account
     pub owner: Pubkey,
-    /// Collateral balance (in lamports/native token)
+    /// Collateral balance (in collateral base units)
     pub collateral_balance: u64,
-    /// Current position (only one position per user for simplicity)
-    pub position: Position,
+    /// Open positions, at most one per market
+    pub positions: [Position; MAX_POSITIONS],
+    /// Number of positions opened so far, used to number new positions
+    pub positions_opened: u64,
     /// Funding payments owed/earned
     pub funding_payment: i64,
     /// Total fees paid
@@ -63,37 +820,80 @@ impl UserAccount {
     pub const SPACE: usize = 8 + // discriminator
         32 + // owner
         8 + // collateral_balance
-        Position::SPACE + // position
+        Position::SPACE * MAX_POSITIONS + // positions
+        8 + // positions_opened
         8 + // funding_payment
         8 + // total_fees_paid
         8; // created_at
+
+    /// Slot of the open position on `market_index`
+    pub fn position_index(&self, market_index: u16) -> Result<usize> {
+        self.positions
+            .iter()
+            .position(|p| p.is_open && p.market_index == market_index)
+            .ok_or_else(|| error!(PerpExchangeError::NoPosition))
+    }
+
+    /// Free slot for a new position on `market_index`
+    pub fn free_position_index(&self, market_index: u16) -> Result<usize> {
+        require!(
+            !self.positions.iter().any(|p| p.is_open && p.market_index == market_index),
+            PerpExchangeError::PositionExists
+        );
+        self.positions
+            .iter()
+            .position(|p| !p.is_open)
+            .ok_or_else(|| error!(PerpExchangeError::TooManyPositions))
+    }
+
+    /// Slot a fill on `market_index` would land in: the open position in the same
+    /// direction, or a free slot. `None` if the fill cannot be taken.
+    pub fn fill_position_index(&self, market_index: u16, is_long: bool) -> Option<usize> {
+        match self.positions.iter().position(|p| p.is_open && p.market_index == market_index) {
+            Some(index) if self.positions[index].is_long() == is_long => Some(index),
+            Some(_) => None,
+            None => self.positions.iter().position(|p| !p.is_open),
+        }
+    }
 }
 
 /// Position data structure
 #[derive(AnchorSerialize, AnchorDeserialize, Clone, Default)]
 pub struct Position {
+    /// Market this position is open on
+    pub market_index: u16,
     /// Position size (signed: positive = long, negative = short)
     pub size: i64,
     /// Margin amount
     pub margin: u64,
     /// Entry price
-    pub entry_price: u64,
+    pub entry_price: Price,
     /// Position is active
     pub is_open: bool,
     /// Leverage used
     pub leverage: u8,
     /// Timestamp when position was opened
     pub opened_at: i64,
+    /// Number of this position among those opened by the owner, never reused
+    pub sequence: u64,
+    /// Market cumulative funding index when funding was last settled
+    pub funding_index: i128,
+    /// Oracle source of the index price the last fill was anchored to
+    pub price_source: OracleSource,
 }
 
 impl Position {
     pub const SPACE: usize = 
+        2 + // market_index
         8 + // size
         8 + // margin
         8 + // entry_price
         1 + // is_open
         1 + // leverage
-        8; // opened_at
+        8 + // opened_at
+        8 + // sequence
+        16 + // funding_index
+        1; // price_source
 
     pub fn is_long(&self) -> bool {
         self.size > 0
@@ -104,7 +904,56 @@ impl Position {
     }
 
     pub fn get_abs_size(&self) -> u64 {
-        self.size.abs() as u64
+        self.size.unsigned_abs()
+    }
+
+    /// Recompute the effective leverage after size or margin changed
+    pub fn refresh_leverage(&mut self) {
+        self.leverage = self.get_abs_size()
+            .checked_div(self.margin)
+            .map_or(u8::MAX, |leverage| leverage.min(u8::MAX as u64) as u8);
+    }
+
+    /// Funding owed since the last settlement given the market's `funding_index`,
+    /// rounded up (positive = the position pays, negative = it receives)
+    pub fn funding_owed(&self, funding_index: i128) -> Result<i128> {
+        let owed = (self.size as i128)
+            .checked_mul(
+                funding_index
+                    .checked_sub(self.funding_index)
+                    .ok_or(PerpExchangeError::MathOverflow)?,
+            )
+            .ok_or(PerpExchangeError::MathOverflow)?;
+        Ok(-(-owed).div_euclid(FUNDING_RATE_PRECISION))
+    }
+
+    /// Notional value of the position at `current_price`, rounded up so margin
+    /// requirements derived from it are never understated
+    pub fn notional_at(&self, current_price: Price) -> Result<u64> {
+        let notional = mul_div(
+            self.get_abs_size() as u128,
+            u128::from(current_price),
+            u128::from(self.entry_price),
+            Rounding::Up,
+        )?;
+        u64::try_from(notional).map_err(|_| error!(PerpExchangeError::MathOverflow))
+    }
+
+    /// Unrealized P&L of the position at `current_price`
+    pub fn calculate_pnl(&self, current_price: Price) -> Result<i128> {
+        self.calculate_pnl_for_size(self.get_abs_size(), current_price)
+    }
+
+    /// Unrealized P&L of `abs_size` out of the position at `current_price`. Gains
+    /// are rounded down and losses up, so the position never gains from rounding.
+    pub fn calculate_pnl_for_size(&self, abs_size: u64, current_price: Price) -> Result<i128> {
+        let price_diff = if self.is_long() {
+            i128::from(current_price) - i128::from(self.entry_price)
+        } else {
+            i128::from(self.entry_price) - i128::from(current_price)
+        };
+
+        mul_div_signed(abs_size as i128, price_diff, i128::from(self.entry_price), Rounding::Down)
     }
 }
 
@@ -113,26 +962,99 @@ impl Position {
 pub struct GovernanceParams {
     /// Trading fee rate (in basis points, e.g., 10 = 0.1%)
     pub trading_fee_rate: u16,
-    /// Liquidation threshold (in basis points, e.g., 8000 = 80%)
-    pub liquidation_threshold: u16,
+    /// Initial margin as a fraction of notional, required to open, add to or
+    /// withdraw from positions (in basis points, e.g., 1000 = 10%)
+    pub initial_margin_rate: u16,
+    /// Maintenance margin as a fraction of notional; positions whose equity falls
+    /// below it can be liquidated (in basis points, e.g., 500 = 5%)
+    pub maintenance_margin_rate: u16,
+    /// Margin above the maintenance margin, as a fraction of notional, that a
+    /// partial liquidation restores (in basis points)
+    pub liquidation_buffer_rate: u16,
+    /// Share of the margin backing the liquidated size paid to the liquidator (in basis points)
+    pub liquidation_reward_rate: u16,
+    /// Share of the released margin paid to the keeper executing a trigger order (in basis points)
+    pub keeper_fee_rate: u16,
     /// Maximum leverage allowed
     pub max_leverage: u8,
-    /// Minimum margin requirement
+    /// Minimum margin requirement (in collateral base units)
     pub min_margin: u64,
+    /// Open interest skew (in collateral base units) at which the fill premium
+    /// reaches 100%, 0 disables the premium
+    pub skew_scale: u64,
     /// Funding rate update interval (seconds)
     pub funding_interval: u32,
+    /// Maximum funding rate per interval, either direction (in basis points)
+    pub max_funding_rate: u16,
+    /// Divisor applied to the mark premium to get the funding rate
+    pub funding_dampening: u16,
     /// Price oracle validity period (seconds)
     pub oracle_validity_period: u32,
+    /// Time over which the mark price EMA follows the index and fill prices (seconds, 0 = no smoothing)
+    pub mark_ema_period: u32,
+    /// Largest oracle confidence interval accepted, as a fraction of the price (in basis points)
+    pub max_confidence_rate: u16,
+    /// Largest oracle price move accepted in one update (in basis points, 0 = unlimited)
+    pub max_price_move_rate: u16,
+    /// Largest oracle price move accepted within one price window (in basis points, 0 = unlimited)
+    pub max_window_move_rate: u16,
+    /// Length of the circuit breaker price window (seconds)
+    pub price_window: u32,
+    /// Largest distance of an order book fill price from the index price, also the
+    /// band fill prices are clamped to before entering the mark price (in basis points)
+    pub max_fill_deviation_rate: u16,
 }
 
 impl GovernanceParams {
     pub const SPACE: usize = 
         2 + // trading_fee_rate
-        2 + // liquidation_threshold
+        2 + // initial_margin_rate
+        2 + // maintenance_margin_rate
+        2 + // liquidation_buffer_rate
+        2 + // liquidation_reward_rate
+        2 + // keeper_fee_rate
         1 + // max_leverage
         8 + // min_margin
+        8 + // skew_scale
         4 + // funding_interval
-        4; // oracle_validity_period
+        2 + // max_funding_rate
+        2 + // funding_dampening
+        4 + // oracle_validity_period
+        4 + // mark_ema_period
+        2 + // max_confidence_rate
+        2 + // max_price_move_rate
+        2 + // max_window_move_rate
+        4 + // price_window
+        2; // max_fill_deviation_rate
+
+    pub fn validate(&self) -> Result<()> {
+        require!(self.max_leverage > 0, PerpExchangeError::InvalidLeverage);
+        require!(
+            self.trading_fee_rate <= 10000
+                && self.liquidation_reward_rate <= 10000
+                && self.keeper_fee_rate <= 10000
+                && self.max_funding_rate <= 10000
+                && self.max_confidence_rate <= 10000
+                && self.max_price_move_rate <= 10000
+                && self.max_window_move_rate <= 10000
+                && self.max_fill_deviation_rate > 0
+                && self.max_fill_deviation_rate <= 10000,
+            PerpExchangeError::InvalidParameter
+        );
+        require!(
+            self.maintenance_margin_rate > 0
+                && self.maintenance_margin_rate <= self.initial_margin_rate
+                && self.initial_margin_rate <= 10000
+                && self.maintenance_margin_rate as u32 + self.liquidation_buffer_rate as u32 <= 10000,
+            PerpExchangeError::InvalidParameter
+        );
+        require!(self.oracle_validity_period > 0, PerpExchangeError::InvalidParameter);
+        require!(
+            self.funding_interval > 0 && self.funding_dampening > 0,
+            PerpExchangeError::InvalidParameter
+        );
+        Ok(())
+    }
 }
 
 /// Vault account for holding collateral - equivalent to Solidity Vault contract
@@ -141,10 +1063,14 @@ impl GovernanceParams {
 pub struct VaultAccount {
     /// Exchange state this vault belongs to
     pub exchange_state: Pubkey,
+    /// Token account holding the collateral, owned by the vault PDA
+    pub token_account: Pubkey,
     /// Total balance in the vault
     pub total_balance: u64,
     /// Reserved collateral (for open positions)
     pub reserved_collateral: u64,
+    /// Counterparty pool that pays out trader profits and absorbs trader losses
+    pub pnl_pool: u64,
     /// Vault bump seed
     pub bump: u8,
 }
@@ -152,7 +1078,1079 @@ pub struct VaultAccount {
 impl VaultAccount {
     pub const SPACE: usize = 8 + // discriminator
         32 + // exchange_state
+        32 + // token_account
         8 + // total_balance
         8 + // reserved_collateral
+        8 + // pnl_pool
+        1; // bump
+
+    /// Check the ledger against the tokens actually held by the vault token account.
+    /// Unsolicited transfers can only add tokens, so only a shortfall is a mismatch.
+    pub fn check_reconciled(&self, custody_amount: u64) -> Result<()> {
+        require!(
+            custody_amount >= self.total_balance,
+            PerpExchangeError::VaultBalanceMismatch
+        );
+        Ok(())
+    }
+}
+
+/// Insurance fund holding the equity left over from liquidations
+#[account]
+#[derive(Default)]
+pub struct InsuranceFund {
+    /// Exchange state this fund belongs to
+    pub exchange_state: Pubkey,
+    /// Token account holding the fund, owned by the insurance fund PDA
+    pub token_account: Pubkey,
+    /// Collateral contributed to the fund
+    pub balance: u64,
+    /// Insurance fund bump seed
+    pub bump: u8,
+}
+
+impl InsuranceFund {
+    pub const SPACE: usize = 8 + // discriminator
+        32 + // exchange_state
+        32 + // token_account
+        8 + // balance
         1; // bump
 }
+
+/// Resting limit order in an order book slab
+#[zero_copy]
+#[derive(Default)]
+pub struct Order {
+    /// User that placed the order
+    pub owner: Pubkey,
+    /// Sequence number, lower is older
+    pub order_id: u64,
+    /// Limit price
+    pub price: Price,
+    /// Remaining size (notional, in collateral base units)
+    pub size: u64,
+    /// Margin posted for the remaining size when filled
+    pub margin: u64,
+    /// Collateral locked for the remaining margin and trading fee
+    pub reserved: u64,
+    /// Timestamp when the order was placed
+    pub placed_at: i64,
+    /// Leverage requested
+    pub leverage: u8,
+    pub _padding: [u8; 7],
+}
+
+/// Per-market limit order book, bids sorted best (highest) first and asks
+/// best (lowest) first, with time priority at equal prices
+#[account(zero_copy)]
+pub struct OrderBook {
+    /// Market this book belongs to
+    pub market: Pubkey,
+    /// Next order id to hand out
+    pub next_order_id: u64,
+    /// Index of the market this book belongs to
+    pub market_index: u16,
+    /// Number of resting bids
+    pub bid_count: u16,
+    /// Number of resting asks
+    pub ask_count: u16,
+    pub _padding: [u8; 2],
+    pub bids: [Order; MAX_ORDERS_PER_SIDE],
+    pub asks: [Order; MAX_ORDERS_PER_SIDE],
+}
+
+impl OrderBook {
+    pub const SPACE: usize = 8 + std::mem::size_of::<OrderBook>();
+
+    fn side_mut(&mut self, is_bid: bool) -> (&mut [Order; MAX_ORDERS_PER_SIDE], &mut u16) {
+        if is_bid {
+            (&mut self.bids, &mut self.bid_count)
+        } else {
+            (&mut self.asks, &mut self.ask_count)
+        }
+    }
+
+    pub fn orders(&self, is_bid: bool) -> &[Order] {
+        if is_bid {
+            &self.bids[..self.bid_count as usize]
+        } else {
+            &self.asks[..self.ask_count as usize]
+        }
+    }
+
+    pub fn best_bid(&self) -> Option<Order> {
+        self.orders(true).first().copied()
+    }
+
+    pub fn best_ask(&self) -> Option<Order> {
+        self.orders(false).first().copied()
+    }
+
+    /// Side and slot of the resting order with `order_id`
+    pub fn find(&self, order_id: u64) -> Option<(bool, usize)> {
+        [true, false].into_iter().find_map(|is_bid| {
+            self.orders(is_bid)
+                .iter()
+                .position(|order| order.order_id == order_id)
+                .map(|index| (is_bid, index))
+        })
+    }
+
+    /// Whether an order at `price` would cross one of `owner`'s own resting orders
+    pub fn crosses_own_order(&self, owner: &Pubkey, is_bid: bool, price: Price) -> bool {
+        self.orders(!is_bid).iter().any(|order| {
+            order.owner == *owner && if is_bid { price >= order.price } else { price <= order.price }
+        })
+    }
+
+    /// Insert an order behind every order with the same or better price
+    pub fn insert(&mut self, is_bid: bool, order: Order) -> Result<()> {
+        let (orders, count) = self.side_mut(is_bid);
+        let len = *count as usize;
+        require!(len < MAX_ORDERS_PER_SIDE, PerpExchangeError::OrderBookFull);
+
+        let index = orders[..len]
+            .iter()
+            .position(|resting| {
+                if is_bid { order.price > resting.price } else { order.price < resting.price }
+            })
+            .unwrap_or(len);
+
+        orders.copy_within(index..len, index + 1);
+        orders[index] = order;
+        *count += 1;
+        Ok(())
+    }
+
+    pub fn remove(&mut self, is_bid: bool, index: usize) -> Order {
+        let (orders, count) = self.side_mut(is_bid);
+        let len = *count as usize;
+        let order = orders[index];
+
+        orders.copy_within(index + 1..len, index);
+        orders[len - 1] = Order::default();
+        *count -= 1;
+        order
+    }
+
+    pub fn order_mut(&mut self, is_bid: bool, index: usize) -> &mut Order {
+        &mut self.side_mut(is_bid).0[index]
+    }
+}
+
+/// Kind of exit condition attached to a position
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq, Default)]
+pub enum TriggerKind {
+    #[default]
+    StopLoss,
+    TakeProfit,
+    /// Stop that follows the price by `trail_distance` as it moves in the position's favour
+    TrailingStop,
+}
+
+/// Side of the trigger price the oracle has to reach
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq, Default)]
+pub enum TriggerDirection {
+    /// Trigger when the price is at or above the trigger price
+    #[default]
+    Above,
+    /// Trigger when the price is at or below the trigger price
+    Below,
+}
+
+/// Exit order executed by keepers once its condition is met
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Default)]
+pub struct TriggerOrder {
+    /// Sequence number within the owner's trigger orders
+    pub order_id: u64,
+    /// Market of the position this order exits
+    pub market_index: u16,
+    /// Sequence number of the position this order exits, so the order cannot
+    /// fire against a later position on the same market
+    pub position_sequence: u64,
+    pub kind: TriggerKind,
+    pub direction: TriggerDirection,
+    /// Price at which the order triggers
+    pub trigger_price: Price,
+    /// Distance kept between the best price seen and the trigger price (trailing stops only)
+    pub trail_distance: Price,
+    /// Worst fill price accepted when executed: the lowest for a long's exit, the
+    /// highest for a short's
+    pub limit_price: Option<Price>,
+    /// Size to close when triggered, 0 closes the whole position
+    pub size: u64,
+    /// Order is live
+    pub is_active: bool,
+}
+
+impl TriggerOrder {
+    pub const SPACE: usize =
+        8 + // order_id
+        2 + // market_index
+        8 + // position_sequence
+        1 + // kind
+        1 + // direction
+        8 + // trigger_price
+        8 + // trail_distance
+        1 + 8 + // limit_price
+        8 + // size
+        1; // is_active
+
+    /// Whether `position` is the one this order was placed on
+    pub fn exits(&self, position: &Position) -> bool {
+        position.is_open
+            && position.market_index == self.market_index
+            && position.sequence == self.position_sequence
+    }
+
+    pub fn is_triggered(&self, price: Price) -> bool {
+        match self.direction {
+            TriggerDirection::Above => price >= self.trigger_price,
+            TriggerDirection::Below => price <= self.trigger_price,
+        }
+    }
+
+    /// Move a trailing stop's trigger price towards `price`; never moves it back
+    pub fn ratchet(&mut self, price: Price) {
+        if self.kind != TriggerKind::TrailingStop {
+            return;
+        }
+        match self.direction {
+            TriggerDirection::Below => {
+                self.trigger_price = self.trigger_price.max(price.saturating_sub(self.trail_distance));
+            }
+            TriggerDirection::Above => {
+                self.trigger_price = self.trigger_price.min(price.saturating_add(self.trail_distance));
+            }
+        }
+    }
+}
+
+/// Trigger orders of one user
+#[account]
+#[derive(Default)]
+pub struct TriggerOrders {
+    /// Owner of these orders
+    pub owner: Pubkey,
+    /// Next order id to hand out
+    pub next_order_id: u64,
+    pub orders: [TriggerOrder; MAX_TRIGGER_ORDERS],
+}
+
+impl TriggerOrders {
+    pub const SPACE: usize = 8 + // discriminator
+        32 + // owner
+        8 + // next_order_id
+        TriggerOrder::SPACE * MAX_TRIGGER_ORDERS; // orders
+
+    /// Slot of the active order with `order_id`
+    pub fn order_index(&self, order_id: u64) -> Result<usize> {
+        self.orders
+            .iter()
+            .position(|order| order.is_active && order.order_id == order_id)
+            .ok_or_else(|| error!(PerpExchangeError::OrderNotFound))
+    }
+}
+
+/// Inputs and result of one funding update
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Default)]
+pub struct FundingRecord {
+    /// Rate applied per interval (scaled by FUNDING_RATE_PRECISION)
+    pub funding_rate: i64,
+    /// Mark premium over the oracle price the rate was derived from
+    pub premium: i64,
+    /// Open interest at the time of the update
+    pub long_open_interest: u64,
+    pub short_open_interest: u64,
+    /// Number of intervals the rate was applied for
+    pub intervals: u32,
+    /// Timestamp of the update
+    pub timestamp: i64,
+}
+
+impl FundingRecord {
+    pub const SPACE: usize =
+        8 + // funding_rate
+        8 + // premium
+        8 + 8 + // open interest
+        4 + // intervals
+        8; // timestamp
+}
+
+/// Ring buffer of a market's most recent funding updates
+#[account]
+pub struct FundingHistory {
+    /// Market this history belongs to
+    pub market_index: u16,
+    /// Slot the next record is written to
+    pub head: u16,
+    /// Number of records stored, at most FUNDING_HISTORY_LEN
+    pub len: u16,
+    pub records: [FundingRecord; FUNDING_HISTORY_LEN],
+}
+
+impl FundingHistory {
+    pub const SPACE: usize = 8 + // discriminator
+        2 + // market_index
+        2 + // head
+        2 + // len
+        FundingRecord::SPACE * FUNDING_HISTORY_LEN; // records
+
+    /// Append a record, overwriting the oldest once the buffer is full
+    pub fn push(&mut self, record: FundingRecord) {
+        self.records[self.head as usize] = record;
+        self.head = ((self.head as usize + 1) % FUNDING_HISTORY_LEN) as u16;
+        self.len = (self.len + 1).min(FUNDING_HISTORY_LEN as u16);
+    }
+}
+
+/// Index price accepted at a point in time
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Default)]
+pub struct PriceObservation {
+    pub price: Price,
+    pub timestamp: i64,
+}
+
+impl PriceObservation {
+    pub const SPACE: usize =
+        8 + // price
+        8; // timestamp
+}
+
+/// Ring buffer of a market's most recent index prices, for time-weighted averages
+#[account]
+pub struct PriceHistory {
+    /// Market this history belongs to
+    pub market_index: u16,
+    /// Slot the next observation is written to
+    pub head: u16,
+    /// Number of observations stored, at most PRICE_HISTORY_LEN
+    pub len: u16,
+    pub observations: [PriceObservation; PRICE_HISTORY_LEN],
+}
+
+impl PriceHistory {
+    pub const SPACE: usize = 8 + // discriminator
+        2 + // market_index
+        2 + // head
+        2 + // len
+        PriceObservation::SPACE * PRICE_HISTORY_LEN; // observations
+
+    /// Drop all observations, e.g. when the market is listed
+    pub fn clear(&mut self) {
+        self.head = 0;
+        self.len = 0;
+        self.observations = [PriceObservation::default(); PRICE_HISTORY_LEN];
+    }
+
+    /// Record a price, overwriting the oldest observation once the buffer is full.
+    /// A second price at the same timestamp replaces the first.
+    pub fn record(&mut self, price: Price, timestamp: i64) {
+        if let Some(latest) = self.observation(0) {
+            if latest.timestamp == timestamp {
+                let latest_index = (self.head as usize + PRICE_HISTORY_LEN - 1) % PRICE_HISTORY_LEN;
+                self.observations[latest_index].price = price;
+                return;
+            }
+        }
+        self.observations[self.head as usize] = PriceObservation { price, timestamp };
+        self.head = ((self.head as usize + 1) % PRICE_HISTORY_LEN) as u16;
+        self.len = (self.len + 1).min(PRICE_HISTORY_LEN as u16);
+    }
+
+    /// The `age`-th most recent observation, 0 being the latest
+    pub fn observation(&self, age: usize) -> Option<PriceObservation> {
+        if age >= self.len as usize {
+            return None;
+        }
+        let index = (self.head as usize + PRICE_HISTORY_LEN - 1 - age) % PRICE_HISTORY_LEN;
+        Some(self.observations[index])
+    }
+
+    /// Time-weighted average price over the `window` seconds before `now`. Each
+    /// observation counts until the next one. Fails if the buffer has already
+    /// overwritten observations from inside the window.
+    pub fn twap(&self, now: i64, window: u32) -> Result<Price> {
+        let latest = self.observation(0).ok_or(PerpExchangeError::InsufficientPriceHistory)?;
+        let start = now - window as i64;
+
+        let mut weighted_sum: u128 = 0;
+        let mut end = now;
+        let mut covered_from = now;
+        for observation in (0..self.len as usize).filter_map(|age| self.observation(age)) {
+            let from = observation.timestamp.max(start);
+            if from < end {
+                weighted_sum += u128::from(observation.price) * (end - from) as u128;
+                covered_from = from;
+            }
+            end = observation.timestamp;
+            if end <= start {
+                break;
+            }
+        }
+
+        // Everything before the oldest kept observation is unknown once the buffer wrapped
+        require!(
+            covered_from <= start || (self.len as usize) < PRICE_HISTORY_LEN,
+            PerpExchangeError::InsufficientPriceHistory
+        );
+
+        let covered = (now - covered_from) as u128;
+        if covered == 0 {
+            return Ok(latest.price);
+        }
+        Price::try_from(weighted_sum / covered)
+    }
+}
+
+/// Latest price posted by one publisher
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Default)]
+pub struct PriceSubmission {
+    /// Key allowed to post this slot's price
+    pub publisher: Pubkey,
+    /// Raw price, in units of 10^`price_exponent` of the market
+    pub price: u64,
+    /// Timestamp of the last submission, 0 if none yet
+    pub submitted_at: i64,
+}
+
+impl PriceSubmission {
+    pub const SPACE: usize =
+        32 + // publisher
+        8 + // price
+        8; // submitted_at
+}
+
+/// Authorized price publishers of a market
+#[account]
+#[derive(Default)]
+pub struct PublisherSet {
+    /// Market this set prices
+    pub market_index: u16,
+    /// Fresh submissions needed before the market price is updated
+    pub quorum: u8,
+    /// Seconds after which a publisher's submission no longer counts
+    pub heartbeat: u32,
+    /// Number of publishers in `submissions`
+    pub publisher_count: u8,
+    pub submissions: [PriceSubmission; MAX_PUBLISHERS],
+}
+
+impl PublisherSet {
+    pub const SPACE: usize = 8 + // discriminator
+        2 + // market_index
+        1 + // quorum
+        4 + // heartbeat
+        1 + // publisher_count
+        PriceSubmission::SPACE * MAX_PUBLISHERS; // submissions
+
+    pub fn publishers(&self) -> &[PriceSubmission] {
+        &self.submissions[..self.publisher_count as usize]
+    }
+
+    /// Slot of `publisher` in the set
+    pub fn publisher_index(&self, publisher: &Pubkey) -> Result<usize> {
+        self.publishers()
+            .iter()
+            .position(|submission| submission.publisher == *publisher)
+            .ok_or_else(|| error!(PerpExchangeError::UnauthorizedPublisher))
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        require!(
+            self.quorum > 0 && self.quorum as usize <= MAX_PUBLISHERS && self.heartbeat > 0,
+            PerpExchangeError::InvalidParameter
+        );
+        Ok(())
+    }
+
+    /// Median of the submissions made within the heartbeat, `None` below quorum.
+    /// With an even count the two middle prices are averaged, rounding down.
+    pub fn median_price(&self, now: i64) -> Option<u64> {
+        let mut prices = [0u64; MAX_PUBLISHERS];
+        let mut count = 0;
+        for submission in self.publishers() {
+            if submission.submitted_at > 0 && now - submission.submitted_at <= self.heartbeat as i64 {
+                prices[count] = submission.price;
+                count += 1;
+            }
+        }
+        if count == 0 || count < self.quorum as usize {
+            return None;
+        }
+
+        let prices = &mut prices[..count];
+        prices.sort_unstable();
+        let mid = count / 2;
+        if count % 2 == 1 {
+            Some(prices[mid])
+        } else {
+            Some(((prices[mid - 1] as u128 + prices[mid] as u128) / 2) as u64)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::constants::PRICE_PRECISION;
+
+    const SIZE: u64 = 1_000_000;
+    const MARGIN: u64 = 100_000;
+    const ONE: Price = Price::new(PRICE_PRECISION);
+
+    fn market_at(mark_price: Price) -> Market {
+        Market {
+            oracle_price: mark_price,
+            mark_price_ema: mark_price,
+            governance_params: GovernanceParams {
+                initial_margin_rate: 1000,
+                maintenance_margin_rate: 500,
+                liquidation_buffer_rate: 100,
+                liquidation_reward_rate: 100,
+                min_margin: 10,
+                ..Default::default()
+            },
+            ..Default::default()
+        }
+    }
+
+    fn long_position() -> Position {
+        Position {
+            size: SIZE as i64,
+            margin: MARGIN,
+            entry_price: ONE,
+            is_open: true,
+            leverage: 10,
+            ..Default::default()
+        }
+    }
+
+    /// Price `bps` basis points below the entry
+    fn down_bps(bps: u64) -> Price {
+        Price::new(PRICE_PRECISION - PRICE_PRECISION * bps / 10000)
+    }
+
+    #[test]
+    fn partial_liquidation_restores_maintenance_plus_buffer() {
+        // 9% down: equity 10_000 against a 45_500 maintenance margin
+        let market = market_at(down_bps(900));
+        let position = long_position();
+        assert!(market.is_liquidatable(&position).unwrap());
+
+        let close_size = market.liquidation_size(&position).unwrap();
+        assert!(close_size > 0 && close_size < SIZE);
+
+        let liquidated = market.partially_liquidated(&position, close_size).unwrap().unwrap();
+        assert_eq!(liquidated.get_abs_size(), SIZE - close_size);
+        assert!(liquidated.is_long());
+        assert!(!market.is_liquidatable(&liquidated).unwrap());
+
+        let target_rate = (market.governance_params.maintenance_margin_rate
+            + market.governance_params.liquidation_buffer_rate) as i128;
+        let notional = liquidated.notional_at(market.mark_price_ema).unwrap() as i128;
+        let equity = market.position_equity(&liquidated).unwrap();
+        assert!(equity * 10000 >= target_rate * notional);
+
+        // Only the rounding slack beyond the exact size of 83.209%
+        assert!(close_size <= SIZE * 8322 / 10000);
+    }
+
+    #[test]
+    fn partial_liquidation_of_short_restores_maintenance_plus_buffer() {
+        let market = market_at(Price::new(PRICE_PRECISION + PRICE_PRECISION * 9 / 100));
+        let position = Position { size: -(SIZE as i64), ..long_position() };
+        assert!(market.is_liquidatable(&position).unwrap());
+
+        let close_size = market.liquidation_size(&position).unwrap();
+        assert!(close_size < SIZE);
+        let liquidated = market.partially_liquidated(&position, close_size).unwrap().unwrap();
+        assert!(liquidated.is_short());
+
+        let notional = liquidated.notional_at(market.mark_price_ema).unwrap() as i128;
+        assert!(market.position_equity(&liquidated).unwrap() * 10000 >= 600 * notional);
+    }
+
+    #[test]
+    fn healthy_position_is_not_liquidatable() {
+        let market = market_at(down_bps(400));
+        assert!(!market.is_liquidatable(&long_position()).unwrap());
+    }
+
+    #[test]
+    fn underwater_position_is_closed_in_full() {
+        // 11% down: equity is negative
+        let market = market_at(down_bps(1100));
+        let position = long_position();
+        assert!(market.position_equity(&position).unwrap() <= 0);
+        assert_eq!(market.liquidation_size(&position).unwrap(), SIZE);
+    }
+
+    #[test]
+    fn closed_in_full_when_reward_outweighs_relief() {
+        let mut market = market_at(down_bps(900));
+        market.governance_params.liquidation_reward_rate = 10000;
+        assert_eq!(market.liquidation_size(&long_position()).unwrap(), SIZE);
+    }
+
+    #[test]
+    fn closed_in_full_when_remaining_margin_below_minimum() {
+        let mut market = market_at(down_bps(900));
+        let position = long_position();
+        let close_size = market.liquidation_size(&position).unwrap();
+        let remaining_margin = market.partially_liquidated(&position, close_size).unwrap().unwrap().margin;
+
+        market.governance_params.min_margin = remaining_margin + 1;
+        assert_eq!(market.liquidation_size(&position).unwrap(), SIZE);
+    }
+
+    #[test]
+    fn liquidation_reward_scales_with_close_size() {
+        let market = market_at(down_bps(900));
+        let position = long_position();
+
+        let full = market.liquidation_reward(&position, SIZE).unwrap();
+        assert_eq!(full, MARGIN / 100);
+        assert_eq!(market.liquidation_reward(&position, SIZE / 2).unwrap(), full / 2);
+        assert_eq!(market.liquidation_reward(&position, SIZE / 4).unwrap(), full / 4);
+        assert_eq!(market.liquidation_reward(&position, 0).unwrap(), 0);
+    }
+
+    fn publisher_set(quorum: u8, submissions: &[(u64, i64)]) -> PublisherSet {
+        let mut set = PublisherSet {
+            quorum,
+            heartbeat: 60,
+            publisher_count: submissions.len() as u8,
+            ..Default::default()
+        };
+        for (slot, &(price, submitted_at)) in submissions.iter().enumerate() {
+            set.submissions[slot] = PriceSubmission { publisher: Pubkey::new_unique(), price, submitted_at };
+        }
+        set
+    }
+
+    #[test]
+    fn median_price_takes_the_middle_submission() {
+        let set = publisher_set(1, &[(105, 1_000), (99, 1_000), (101, 1_000)]);
+        assert_eq!(set.median_price(1_000), Some(101));
+    }
+
+    #[test]
+    fn median_price_averages_the_middle_pair_rounding_down() {
+        let set = publisher_set(1, &[(100, 1_000), (103, 1_000), (90, 1_000), (200, 1_000)]);
+        assert_eq!(set.median_price(1_000), Some(101));
+    }
+
+    #[test]
+    fn median_price_ignores_stale_and_missing_submissions() {
+        // The 500 is past the heartbeat and the empty slot never submitted
+        let set = publisher_set(2, &[(100, 1_000), (500, 900), (102, 1_010), (0, 0)]);
+        assert_eq!(set.median_price(1_020), Some(101));
+    }
+
+    #[test]
+    fn median_price_waits_for_quorum() {
+        let set = publisher_set(3, &[(100, 1_000), (102, 1_000), (104, 900)]);
+        assert_eq!(set.median_price(1_000), None);
+        assert_eq!(publisher_set(1, &[]).median_price(1_000), None);
+    }
+
+    fn price_history(observations: &[(u64, i64)]) -> PriceHistory {
+        let mut history = PriceHistory {
+            market_index: 0,
+            head: 0,
+            len: 0,
+            observations: [PriceObservation::default(); PRICE_HISTORY_LEN],
+        };
+        for &(price, timestamp) in observations {
+            history.record(Price::new(price), timestamp);
+        }
+        history
+    }
+
+    #[test]
+    fn twap_weights_prices_by_time_held() {
+        // 100 for the first 30s of the window, 200 for the last 10s
+        let history = price_history(&[(100, 1_000), (200, 1_030)]);
+        assert_eq!(history.twap(1_040, 40).unwrap(), Price::new(125));
+        // A window inside the latest observation only sees its price
+        assert_eq!(history.twap(1_040, 10).unwrap(), Price::new(200));
+    }
+
+    #[test]
+    fn twap_counts_the_observation_before_the_window() {
+        let history = price_history(&[(100, 900), (300, 1_020)]);
+        assert_eq!(history.twap(1_040, 40).unwrap(), Price::new(200));
+    }
+
+    #[test]
+    fn twap_averages_only_what_is_covered_before_wrapping() {
+        let history = price_history(&[(100, 1_000), (200, 1_010)]);
+        assert_eq!(history.twap(1_020, 3_600).unwrap(), Price::new(150));
+    }
+
+    #[test]
+    fn twap_of_zero_coverage_is_the_latest_price() {
+        let history = price_history(&[(100, 1_000), (200, 1_010)]);
+        assert_eq!(history.twap(1_010, 0).unwrap(), Price::new(200));
+    }
+
+    #[test]
+    fn record_overwrites_a_price_at_the_same_timestamp() {
+        let history = price_history(&[(100, 1_000), (150, 1_000)]);
+        assert_eq!(history.len, 1);
+        assert_eq!(history.observation(0).unwrap().price, Price::new(150));
+    }
+
+    #[test]
+    fn twap_fails_without_history_covering_the_window() {
+        assert!(price_history(&[]).twap(1_000, 60).is_err());
+
+        // Once the buffer wrapped the oldest kept observation bounds the window
+        let observations: Vec<(u64, i64)> = (0..PRICE_HISTORY_LEN as i64 + 1)
+            .map(|index| (100, 1_000 + index * 10))
+            .collect();
+        let history = price_history(&observations);
+        let now = 1_000 + PRICE_HISTORY_LEN as i64 * 10;
+        assert_eq!(history.twap(now, 600).unwrap(), Price::new(100));
+        assert!(history.twap(now, 10 * PRICE_HISTORY_LEN as u32).is_err());
+    }
+
+    fn skewed_market(total_long: u64, total_short: u64) -> Market {
+        let mut market = market_at(ONE);
+        market.governance_params.skew_scale = 1_000;
+        market.total_long_positions = total_long;
+        market.total_short_positions = total_short;
+        market
+    }
+
+    #[test]
+    fn skew_premium_rounds_against_the_trader() {
+        // Longs dominate by 10% of the skew scale
+        let market = skewed_market(100, 0);
+        let price = Price::new(PRICE_PRECISION + 1);
+        assert_eq!(market.apply_skew_premium(price, true, 0).unwrap(), Price::new(1_100_000_002));
+        assert_eq!(market.apply_skew_premium(price, false, 0).unwrap(), Price::new(1_100_000_001));
+
+        // Trades are priced at the skew halfway through them
+        assert_eq!(market.apply_skew_premium(ONE, true, 200).unwrap(), Price::new(1_200_000_000));
+        assert_eq!(market.apply_skew_premium(ONE, false, 200).unwrap(), Price::new(1_000_000_000));
+    }
+
+    #[test]
+    fn skew_premium_is_capped_for_heavily_skewed_books() {
+        let market = skewed_market(0, 5_000);
+        assert_eq!(market.apply_skew_premium(ONE, false, SIZE).unwrap(), Price::new(PRICE_PRECISION / 2));
+        assert_eq!(market.apply_skew_premium(ONE, true, 0).unwrap(), Price::new(PRICE_PRECISION / 2));
+
+        let market = skewed_market(5_000, 0);
+        assert_eq!(market.apply_skew_premium(ONE, true, SIZE).unwrap(), Price::new(PRICE_PRECISION * 3 / 2));
+    }
+
+    #[test]
+    fn zero_skew_scale_disables_the_premium() {
+        let mut market = skewed_market(0, 5_000);
+        market.governance_params.skew_scale = 0;
+        assert_eq!(market.apply_skew_premium(ONE, false, SIZE).unwrap(), Price::new(PRICE_PRECISION));
+    }
+
+    #[test]
+    fn unwinding_a_vamm_position_restores_the_reserves() {
+        let mut market = market_at(ONE);
+        market.pricing_mode = PricingMode::Vamm;
+        market.reset_vamm(1_000_000_000).unwrap();
+        let (quote_reserve, base_reserve) = (market.vamm_quote_reserve, market.vamm_base_reserve);
+
+        let entry_price = market.fill_price(true, SIZE).unwrap();
+        assert!(entry_price > ONE);
+        assert!(market.vamm_base_reserve < base_reserve);
+
+        market.unwind_vamm(true, SIZE, entry_price);
+        assert!(market.vamm_base_reserve.abs_diff(base_reserve) <= 1);
+        assert!(market.vamm_quote_reserve >= quote_reserve);
+        assert!(market.vamm_quote_reserve - quote_reserve <= 1);
+    }
+
+    #[test]
+    fn unwinding_outside_vamm_mode_is_a_no_op() {
+        let mut market = market_at(ONE);
+        market.unwind_vamm(true, SIZE, ONE);
+        assert_eq!(market.vamm_base_reserve, 0);
+        assert_eq!(market.vamm_quote_reserve, 0);
+    }
+
+    #[test]
+    fn repeg_keeps_the_base_open_positions_hold() {
+        let mut market = market_at(ONE);
+        market.pricing_mode = PricingMode::Vamm;
+        market.reset_vamm(1_000_000_000).unwrap();
+
+        // A short takes most of the depth, then the oracle moves and the vAMM is re-pegged
+        let size = 600_000_000;
+        let entry_price = market.fill_price(false, size).unwrap();
+        let base_reserve = market.vamm_base_reserve;
+        market.oracle_price = Price::new(PRICE_PRECISION + PRICE_PRECISION / 20);
+        market.repeg().unwrap();
+        assert_eq!(market.vamm_base_reserve, base_reserve);
+        assert!(market.spot_price().unwrap().abs_diff(market.oracle_price) <= 1);
+
+        // The short can still be closed or liquidated against the re-pegged reserves
+        let mut closed = market.clone();
+        assert!(closed.exit_price(false, size, entry_price).unwrap() > market.oracle_price);
+        let mut liquidated = market.clone();
+        liquidated.unwind_vamm(false, size, entry_price);
+        assert!(liquidated.vamm_base_reserve < base_reserve);
+    }
+
+    #[test]
+    fn unwinding_never_fails_a_liquidation() {
+        let mut market = market_at(ONE);
+        market.pricing_mode = PricingMode::Vamm;
+        market.reset_vamm(1_000_000_000).unwrap();
+        let (quote_reserve, base_reserve) = (market.vamm_quote_reserve, market.vamm_base_reserve);
+
+        // More base than the vAMM holds: the reserves are left alone
+        market.unwind_vamm(false, 2_000_000_000, ONE);
+        assert_eq!(market.vamm_quote_reserve, quote_reserve);
+        assert_eq!(market.vamm_base_reserve, base_reserve);
+    }
+
+    /// Market indexed at 100 by its publishers, with a 10% per-update and 25%
+    /// per-window breaker
+    fn breaker_market() -> Market {
+        let mut market = market_at(Price::new(100 * PRICE_PRECISION));
+        market.oracle_sources[0] = OracleSource::Publishers;
+        market.oracle_source_count = 1;
+        market.oracle_price_source = OracleSource::Publishers;
+        market.oracle_last_update = 1_000;
+        market.price_window_start = 1_000;
+        market.price_window_open_price = market.oracle_price;
+        market.governance_params.oracle_validity_period = 60;
+        market.governance_params.max_price_move_rate = 1000;
+        market.governance_params.max_window_move_rate = 2500;
+        market.governance_params.price_window = 3600;
+        market
+    }
+
+    fn report(market: &mut Market, reporter: Pubkey, price: u64, now: i64) -> PriceUpdate {
+        let price = SourcePrice { price: Price::new(price * PRICE_PRECISION), confidence: 0, timestamp: now, reporter };
+        market.record_source_price(OracleSource::Publishers, price, now).unwrap()
+    }
+
+    #[test]
+    fn second_reporter_corroborates_a_halted_price_at_the_same_value() {
+        let mut market = breaker_market();
+        let (first, second) = (Pubkey::new_unique(), Pubkey::new_unique());
+
+        assert_eq!(report(&mut market, first, 150, 1_010), PriceUpdate::Halted);
+        assert!(market.is_halted);
+        // The same report again is not news
+        assert_eq!(report(&mut market, first, 150, 1_010), PriceUpdate::Unchanged);
+
+        assert_eq!(report(&mut market, second, 150, 1_020), PriceUpdate::Applied);
+        assert!(!market.is_halted);
+        assert_eq!(market.oracle_price, Price::new(150 * PRICE_PRECISION));
+    }
+
+    #[test]
+    fn move_beyond_the_update_limit_halts_and_holds_the_price() {
+        let mut market = breaker_market();
+        let reporter = Pubkey::new_unique();
+
+        assert_eq!(report(&mut market, reporter, 110, 1_010), PriceUpdate::Applied);
+        assert_eq!(report(&mut market, reporter, 122, 1_020), PriceUpdate::Halted);
+        assert!(market.is_halted);
+        assert_eq!(market.oracle_price, Price::new(110 * PRICE_PRECISION));
+        assert_eq!(market.oracle_last_update, 1_010);
+        assert_eq!(market.pending_price, Price::new(122 * PRICE_PRECISION));
+        assert_eq!(market.pending_price_reporter, reporter);
+    }
+
+    #[test]
+    fn moves_adding_up_beyond_the_window_limit_halt() {
+        let mut market = breaker_market();
+        let reporter = Pubkey::new_unique();
+
+        assert_eq!(report(&mut market, reporter, 109, 1_010), PriceUpdate::Applied);
+        assert_eq!(report(&mut market, reporter, 118, 1_020), PriceUpdate::Applied);
+        assert_eq!(report(&mut market, reporter, 127, 1_030), PriceUpdate::Halted);
+        assert_eq!(market.oracle_price, Price::new(118 * PRICE_PRECISION));
+    }
+
+    #[test]
+    fn price_window_restarts_from_the_index_once_it_elapses() {
+        let mut market = breaker_market();
+        let reporter = Pubkey::new_unique();
+
+        assert_eq!(report(&mut market, reporter, 109, 1_010), PriceUpdate::Applied);
+        assert_eq!(report(&mut market, reporter, 118, 1_020), PriceUpdate::Applied);
+        assert_eq!(report(&mut market, reporter, 127, 4_601), PriceUpdate::Applied);
+        assert_eq!(market.price_window_start, 4_601);
+        assert_eq!(market.price_window_open_price, Price::new(118 * PRICE_PRECISION));
+    }
+
+    #[test]
+    fn halted_price_needs_a_different_reporter_close_to_it() {
+        let mut market = breaker_market();
+        let (first, second, third) = (Pubkey::new_unique(), Pubkey::new_unique(), Pubkey::new_unique());
+
+        assert_eq!(report(&mut market, first, 150, 1_010), PriceUpdate::Halted);
+        // The same reporter cannot confirm its own price, it only replaces it
+        assert_eq!(report(&mut market, first, 151, 1_020), PriceUpdate::Halted);
+        assert_eq!(market.pending_price, Price::new(151 * PRICE_PRECISION));
+        // A different reporter far from the pending price replaces it too
+        assert_eq!(report(&mut market, second, 200, 1_030), PriceUpdate::Halted);
+        assert_eq!(market.pending_price_reporter, second);
+
+        assert_eq!(report(&mut market, third, 195, 1_040), PriceUpdate::Applied);
+        assert!(!market.is_halted);
+        assert_eq!(market.oracle_price, Price::new(195 * PRICE_PRECISION));
+        assert_eq!(market.oracle_last_update, 1_040);
+        assert_eq!(market.price_window_start, 1_040);
+        assert_eq!(market.price_window_open_price, Price::new(195 * PRICE_PRECISION));
+    }
+
+    #[test]
+    fn confirming_accepts_the_pending_price() {
+        let mut market = breaker_market();
+        assert!(market.confirm_pending_price(1_010).is_err());
+
+        assert_eq!(report(&mut market, Pubkey::new_unique(), 150, 1_010), PriceUpdate::Halted);
+        assert_eq!(market.confirm_pending_price(1_020).unwrap(), Price::new(150 * PRICE_PRECISION));
+        assert!(!market.is_halted);
+        assert_eq!(market.oracle_price, Price::new(150 * PRICE_PRECISION));
+        assert_eq!(market.oracle_last_update, 1_020);
+        assert_eq!(market.oracle_price_source, OracleSource::Publishers);
+        assert_eq!(market.pending_price, Price::ZERO);
+    }
+
+    #[test]
+    fn halted_market_keeps_the_held_price_only_while_it_is_fresh() {
+        let mut market = breaker_market();
+        assert_eq!(report(&mut market, Pubkey::new_unique(), 150, 1_010), PriceUpdate::Halted);
+
+        // Exits use the held price from 1_000 until it goes stale
+        assert!(market.check_not_halted().is_err());
+        assert!(market.check_oracle_fresh(1_060).is_ok());
+        assert!(market.check_oracle_fresh(1_061).is_err());
+    }
+
+    #[test]
+    fn initial_margin_does_not_credit_a_skew_discount() {
+        // Shorts dominate, so a long of SIZE fills 10% below the index
+        let mut market = market_at(ONE);
+        market.governance_params.skew_scale = 10 * SIZE;
+        market.total_short_positions = SIZE * 3 / 2;
+        let entry_price = market.apply_skew_premium(ONE, true, SIZE).unwrap();
+        assert_eq!(entry_price, Price::new(PRICE_PRECISION * 9 / 10));
+        market.total_long_positions = SIZE;
+
+        // At the mark the long shows a gain of about 111_111, but closing it sells
+        // at the same discount
+        let position = Position { entry_price, margin: 20_000, ..long_position() };
+        assert!(market.position_equity(&position).unwrap() > 111_111);
+        assert!(market.check_initial_margin(&position).is_err());
+
+        let position = Position { margin: 120_000, ..position };
+        assert!(market.check_initial_margin(&position).is_ok());
+
+        // Shorts selling 10% above the index while longs dominate get the mirrored treatment
+        market.total_short_positions = 0;
+        market.total_long_positions = SIZE * 3 / 2;
+        let entry_price = market.apply_skew_premium(ONE, false, SIZE).unwrap();
+        assert_eq!(entry_price, Price::new(PRICE_PRECISION * 11 / 10));
+        market.total_short_positions = SIZE;
+
+        let position = Position { size: -(SIZE as i64), entry_price, margin: 20_000, ..long_position() };
+        assert!(market.position_equity(&position).unwrap() > 90_909);
+        assert!(market.check_initial_margin(&position).is_err());
+
+        let position = Position { margin: 100_000, ..position };
+        assert!(market.check_initial_margin(&position).is_ok());
+    }
+
+    #[test]
+    fn trigger_order_only_exits_the_position_it_was_placed_on() {
+        let position = Position { opened_at: 1_000, sequence: 3, ..long_position() };
+        let order = TriggerOrder { position_sequence: 3, is_active: true, ..Default::default() };
+        assert!(order.exits(&position));
+
+        // Closed and reopened on the same side within the same second
+        let reopened = Position { sequence: 4, ..position.clone() };
+        assert!(!order.exits(&reopened));
+        assert!(!order.exits(&Position::default()));
+        assert!(!order.exits(&Position { market_index: 1, ..position }));
+    }
+
+    fn order(owner: Pubkey, order_id: u64, price: u64) -> Order {
+        Order { owner, order_id, price: Price::new(price), size: SIZE, ..Default::default() }
+    }
+
+    fn order_ids(order_book: &OrderBook, is_bid: bool) -> Vec<u64> {
+        order_book.orders(is_bid).iter().map(|order| order.order_id).collect()
+    }
+
+    #[test]
+    fn order_book_keeps_price_time_priority() {
+        let mut order_book: OrderBook = bytemuck::Zeroable::zeroed();
+        let owner = Pubkey::new_unique();
+        for (order_id, price) in [(0, 100), (1, 101), (2, 100), (3, 99)] {
+            order_book.insert(true, order(owner, order_id, price)).unwrap();
+        }
+        for (order_id, price) in [(4, 105), (5, 104), (6, 105)] {
+            order_book.insert(false, order(owner, order_id, price)).unwrap();
+        }
+
+        // Bids highest first, asks lowest first, older first at equal prices
+        assert_eq!(order_ids(&order_book, true), [1, 0, 2, 3]);
+        assert_eq!(order_ids(&order_book, false), [5, 4, 6]);
+        assert_eq!(order_book.best_bid().unwrap().order_id, 1);
+        assert_eq!(order_book.best_ask().unwrap().order_id, 5);
+    }
+
+    #[test]
+    fn order_book_remove_and_find() {
+        let mut order_book: OrderBook = bytemuck::Zeroable::zeroed();
+        let owner = Pubkey::new_unique();
+        for (order_id, price) in [(0, 100), (1, 99), (2, 98)] {
+            order_book.insert(true, order(owner, order_id, price)).unwrap();
+        }
+        order_book.insert(false, order(owner, 3, 110)).unwrap();
+
+        assert_eq!(order_book.find(1), Some((true, 1)));
+        assert_eq!(order_book.find(3), Some((false, 0)));
+        assert_eq!(order_book.find(4), None);
+
+        assert_eq!(order_book.remove(true, 1).order_id, 1);
+        assert_eq!(order_ids(&order_book, true), [0, 2]);
+        assert_eq!(order_book.bid_count, 2);
+        assert_eq!(order_book.bids[2].order_id, 0);
+        assert_eq!(order_book.find(1), None);
+        assert_eq!(order_book.find(2), Some((true, 1)));
+    }
+
+    #[test]
+    fn order_book_rejects_orders_beyond_capacity() {
+        let mut order_book: OrderBook = bytemuck::Zeroable::zeroed();
+        let owner = Pubkey::new_unique();
+        for order_id in 0..MAX_ORDERS_PER_SIDE as u64 {
+            order_book.insert(false, order(owner, order_id, 100)).unwrap();
+        }
+        assert!(order_book.insert(false, order(owner, 999, 100)).is_err());
+        assert!(order_book.insert(true, order(owner, 999, 90)).is_ok());
+    }
+
+    #[test]
+    fn own_orders_cross_only_at_or_through_their_price() {
+        let mut order_book: OrderBook = bytemuck::Zeroable::zeroed();
+        let (owner, other) = (Pubkey::new_unique(), Pubkey::new_unique());
+        order_book.insert(false, order(owner, 0, 100)).unwrap();
+        order_book.insert(true, order(owner, 1, 90)).unwrap();
+
+        assert!(order_book.crosses_own_order(&owner, true, Price::new(100)));
+        assert!(!order_book.crosses_own_order(&owner, true, Price::new(99)));
+        assert!(order_book.crosses_own_order(&owner, false, Price::new(90)));
+        assert!(!order_book.crosses_own_order(&owner, false, Price::new(91)));
+        assert!(!order_book.crosses_own_order(&other, true, Price::new(100)));
+    }
+}
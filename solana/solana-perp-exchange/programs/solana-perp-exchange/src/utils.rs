@@ -0,0 +1,55 @@
+use anchor_lang::prelude::*;
+use anchor_spl::token::{self, Mint, Token, TokenAccount, TransferChecked};
+use crate::state::VaultAccount;
+use crate::constants::*;
+
+/// Transfer collateral tokens from an account owned by `authority` into the vault
+pub fn transfer_to_vault<'info>(
+    from: &Account<'info, TokenAccount>,
+    vault_token_account: &Account<'info, TokenAccount>,
+    collateral_mint: &Account<'info, Mint>,
+    authority: &Signer<'info>,
+    token_program: &Program<'info, Token>,
+    amount: u64,
+) -> Result<()> {
+    token::transfer_checked(
+        CpiContext::new(
+            token_program.to_account_info(),
+            TransferChecked {
+                from: from.to_account_info(),
+                mint: collateral_mint.to_account_info(),
+                to: vault_token_account.to_account_info(),
+                authority: authority.to_account_info(),
+            },
+        ),
+        amount,
+        collateral_mint.decimals,
+    )
+}
+
+/// Transfer collateral tokens out of the vault, signed by the vault PDA
+pub fn transfer_from_vault<'info>(
+    vault: &Account<'info, VaultAccount>,
+    vault_token_account: &Account<'info, TokenAccount>,
+    to: &Account<'info, TokenAccount>,
+    collateral_mint: &Account<'info, Mint>,
+    token_program: &Program<'info, Token>,
+    amount: u64,
+) -> Result<()> {
+    let signer_seeds: &[&[&[u8]]] = &[&[VAULT_SEED, &[vault.bump]]];
+
+    token::transfer_checked(
+        CpiContext::new_with_signer(
+            token_program.to_account_info(),
+            TransferChecked {
+                from: vault_token_account.to_account_info(),
+                mint: collateral_mint.to_account_info(),
+                to: to.to_account_info(),
+                authority: vault.to_account_info(),
+            },
+            signer_seeds,
+        ),
+        amount,
+        collateral_mint.decimals,
+    )
+}